@@ -0,0 +1,6736 @@
+//! Compiles the shell commands in the code blocks of Markdown files into Bash
+//! scripts that run them and check their results.
+//!
+//! ```
+//! use bashtestmd::{
+//!     compile_commands_into_bash, convert_code_blocks_into_commands, get_all_code_blocks,
+//!     ConversionOptions, ScriptOptions,
+//! };
+//!
+//! let markdown = "```sh,test-ci\n$ echo hello\n```\n";
+//! let ast = markdown::to_mdast(markdown, &markdown::ParseOptions::gfm()).unwrap();
+//! let options = ConversionOptions {
+//!     tag: "test-ci".to_string(),
+//!     ..Default::default()
+//! };
+//! let conversion = convert_code_blocks_into_commands(get_all_code_blocks(ast), &options).unwrap();
+//! let (script, _) = compile_commands_into_bash(conversion.commands, &ScriptOptions::default());
+//! assert!(script.lines().any(|line| line == "echo hello"));
+//! ```
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use indoc::indoc;
+use markdown::mdast;
+use markdown::unist::Position;
+
+/// The warnings about the code blocks of a single conversion.
+struct Diagnostics {
+    /// Don't print the warnings, see `ConversionOptions::quiet`.
+    quiet: bool,
+    /// The number of warnings so far, printed or not.
+    warnings: usize,
+}
+
+/// Prints a warning about the code blocks like `eprintln!` unless they're
+/// quiet, and counts it in the `Diagnostics` either way.
+macro_rules! warn {
+    ($diagnostics:expr, $($arg:tt)*) => {{
+        let diagnostics: &mut Diagnostics = $diagnostics;
+        diagnostics.warnings += 1;
+        if !diagnostics.quiet {
+            eprintln!($($arg)*);
+        }
+    }};
+}
+
+/// The result of `convert_code_blocks_into_commands`.
+pub struct Conversion {
+    /// The commands, in the order they run in.
+    pub commands: Vec<Command>,
+    /// The number of warnings about the code blocks, e.g. for unknown tags
+    /// or blocks without commands.
+    pub warnings: usize,
+}
+
+/// The error of `convert_code_blocks_into_commands`, after the errors in the
+/// code blocks were printed.
+#[derive(Debug)]
+pub struct ConversionError {
+    /// The number of errors.
+    pub errors: usize,
+    /// The number of warnings, which aren't counted in `errors` unless
+    /// they're errors with `ConversionOptions::strict`.
+    pub warnings: usize,
+}
+
+impl std::fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Aborting due to {} error(s)", self.errors)
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// How the script shows that it's still waiting for the text of a
+/// `bashtestmd:wait-until` tag.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum WaitProgress {
+    /// A dot per poll, on a single line
+    #[default]
+    Dots,
+    /// A spinner that turns on every poll
+    Spinner,
+    /// A line with the time per poll
+    Timestamp,
+    /// Nothing
+    Silent,
+}
+
+/// Where the script writes its own messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum MessagesTo {
+    /// Together with the output of the commands
+    #[default]
+    Stdout,
+    /// Separately from the output of the commands
+    Stderr,
+}
+
+impl WaitProgress {
+    /// What the wait loop runs on every poll.
+    fn on_poll(self) -> &'static str {
+        match self {
+            WaitProgress::Dots => r#"bashtestmd_msg echo -n ".""#,
+            WaitProgress::Spinner => concat!(
+                r#"bashtestmd_spinner='|/-\'; bashtestmd_polls=$((bashtestmd_polls + 1)); "#,
+                r#"bashtestmd_msg printf '\r%s' "${bashtestmd_spinner:bashtestmd_polls % 4:1}""#
+            ),
+            WaitProgress::Timestamp => {
+                r#"bashtestmd_msg echo "Still waiting at $(date '+%H:%M:%S')""#
+            }
+            WaitProgress::Silent => ":",
+        }
+    }
+
+    /// What the script runs once the text was found.
+    fn on_done(self) -> &'static str {
+        match self {
+            WaitProgress::Dots => r#"bashtestmd_msg echo """#,
+            WaitProgress::Spinner => r#"bashtestmd_msg printf '\r \r'"#,
+            WaitProgress::Timestamp | WaitProgress::Silent => "",
+        }
+    }
+}
+
+/// A command from a code block, with the checks of the block's tags.
+pub struct Command {
+    cmd: String,
+    long_running: bool,
+    expected_output: Option<String>,
+    /// The output must be exactly `expected_output`, rather than either of
+    /// them containing the other.
+    exact_output: bool,
+    /// How the output and the expected output are normalized before they're
+    /// compared.
+    normalization: Normalization,
+    /// Text that must not appear in the output.
+    unexpected_output: Option<String>,
+    /// Compared with the standard error of the command like
+    /// `expected_output` with the output.
+    expected_stderr: Option<String>,
+    wait_until: Option<String>,
+    /// `wait_until` is an extended regular expression rather than literal
+    /// text, see `bashtestmd:wait-until-regex`.
+    wait_until_regex: bool,
+    /// Seconds between the checks for the `wait_until` text.
+    wait_interval: u64,
+    /// Seconds after which the script stops waiting for the `wait_until`
+    /// text and fails.
+    wait_timeout: Option<u64>,
+    /// Seconds to wait after starting a long running command without
+    /// `wait_until`, instead of `--default-background-delay`.
+    background_delay: Option<u64>,
+    /// The long running command must still run after its `background_delay`,
+    /// see `bashtestmd:daemon`.
+    daemon: bool,
+    exit_code: Option<ExitCode>,
+    expect_diff: Option<String>,
+    trace_to: Option<String>,
+    /// Append to the `trace_to` file instead of overwriting it, for the
+    /// commands of a block after the first.
+    trace_append: bool,
+    expect_sha256: Option<String>,
+    wait_on: WaitOn,
+    expect_no_output_change: bool,
+    /// Run the command this many times in total, and check that the output
+    /// is the same every time.
+    expect_output_unchanged_across: Option<usize>,
+    show_output: bool,
+    expect_output_empty_lines: Option<usize>,
+    pipefail: bool,
+    /// Record the output as the `expect_diff` baseline if there's none yet,
+    /// rather than failing.
+    record_missing_baseline: bool,
+    /// Environment variables set for the command only.
+    env: Vec<(String, String)>,
+    expect_output_matches_command: Option<String>,
+    max_capture_bytes: Option<usize>,
+    env_file: Option<String>,
+    expected_json: Option<String>,
+    cache_key: Option<String>,
+    /// Run the command again if it fails and its output matches this
+    /// pattern, up to `retry_attempts` times in total.
+    retry_on_output: Option<String>,
+    retry_attempts: usize,
+    /// Run the command up to this many times in total until it passes all of
+    /// its checks, see `bashtestmd:retry`.
+    retry: Option<usize>,
+    /// Seconds between the attempts of `retry`.
+    retry_delay: u64,
+    /// Seconds after which the command is terminated and fails, see
+    /// `bashtestmd:timeout`.
+    timeout: Option<u64>,
+    /// File the command reads its standard input from.
+    stdin_file: Option<String>,
+    /// Text the command reads as its standard input, see `bashtestmd:stdin`.
+    stdin: Option<String>,
+    /// JSON Schema file the output must be valid against.
+    expect_schema: Option<String>,
+    /// File with the expected output, compared like `expected_output`.
+    expected_output_file: Option<String>,
+    /// Paths that must not exist after the command ran.
+    expect_no_file: Vec<String>,
+    /// Pairs of files that must be equal after the command ran.
+    expect_files_equal: Vec<(String, String)>,
+    /// Bash expressions that must be true after the command ran, see
+    /// `bashtestmd:assert`.
+    asserts: Vec<String>,
+    /// Named patterns from `PATTERNS` the output must contain a match of.
+    match_patterns: Vec<(&'static str, &'static str)>,
+    /// Extended regular expressions that a line of the output must match, see
+    /// `bashtestmd:output-matches`.
+    output_matches: Vec<String>,
+    /// The group the command is reported in, see `bashtestmd:group`.
+    group: Option<String>,
+    /// The commands of consecutive blocks with the same parallel group run
+    /// at the same time, see `bashtestmd:parallel-group`.
+    parallel_group: Option<String>,
+    /// Report failures without failing the script, see `bashtestmd:flaky`.
+    flaky: bool,
+    /// Report failures as warnings without failing the script, see
+    /// `bashtestmd:allow-fail`.
+    allow_fail: bool,
+    /// Resource limits, as the option of `ulimit` and the value.
+    ulimits: Vec<(char, String)>,
+    /// The signal the command must be terminated by, which sets `exit_code`.
+    expect_signal: Option<&'static str>,
+    /// Run the command in a new `bash` process, see `bashtestmd:isolated`.
+    isolated: bool,
+    /// The command is the whole text of its block, see `bashtestmd:script`.
+    script: bool,
+    /// Directory the script changes to before the command, for it and all
+    /// later commands.
+    chdir: Option<String>,
+    /// Directory the command runs in, after which the script changes back,
+    /// see `bashtestmd:cd`.
+    cwd: Option<String>,
+    /// Run the command when the script exits, however it exits, see
+    /// `bashtestmd:teardown`.
+    teardown: bool,
+    /// The values of the `bashtestmd:matrix` variables the command runs with,
+    /// e.g. `NETWORK=devnet`.
+    params: Option<String>,
+    /// The position of the code block the output is written into with
+    /// `--embed-output`.
+    embed_output: Option<Position>,
+    /// Shell variable the output is stored in, for later commands.
+    capture: Option<String>,
+    /// Expand the variables in the expected output when the script runs.
+    expand_expected: bool,
+    /// Remove the ANSI escape sequences, e.g. colors, from the output before
+    /// it's checked.
+    strip_ansi: bool,
+    /// The line of the command in the Markdown file.
+    line: Option<usize>,
+    /// The Markdown file the command comes from, see `set_file`.
+    file: Option<String>,
+    /// The index of the code block of the command among all code blocks of
+    /// the Markdown file.
+    block: Option<usize>,
+}
+
+/// The output stream(s) of a long running command in which the `wait-until`
+/// text must appear.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WaitOn {
+    Stdout,
+    Stderr,
+    Both,
+}
+
+impl FromStr for WaitOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdout" => Ok(Self::Stdout),
+            "stderr" => Ok(Self::Stderr),
+            "both" => Ok(Self::Both),
+            _ => Err(format!(
+                "invalid stream '{s}', expected one of: stdout, stderr, both"
+            )),
+        }
+    }
+}
+
+/// How the output and the expected output are normalized before the
+/// substring comparison, see `bashtestmd:compare-output-ci` and
+/// `bashtestmd:compare-output-trim`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Normalization {
+    ignore_case: bool,
+    /// Collapse runs of whitespace into a single space, and trim each line.
+    collapse_whitespace: bool,
+}
+
+impl Normalization {
+    /// The shell pipeline that normalizes its input, if there's anything to
+    /// normalize.
+    fn filter(&self) -> Option<String> {
+        let mut filters = Vec::new();
+        if self.ignore_case {
+            filters.push("tr '[:upper:]' '[:lower:]'");
+        }
+        if self.collapse_whitespace {
+            filters.push("sed -E 's/[[:space:]]+/ /g; s/^ //; s/ $//'");
+        }
+        (!filters.is_empty()).then(|| filters.join(" | "))
+    }
+}
+
+/// The exit codes a command is expected to exit with, see
+/// `bashtestmd:exit-code`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExitCode {
+    Exactly(i32),
+    /// An inclusive range, e.g. `1-125`.
+    Range(i32, i32),
+    NonZero,
+}
+
+impl ExitCode {
+    /// The test that `$exit_code` is not one of the expected exit codes.
+    fn mismatch(&self) -> String {
+        match self {
+            Self::Exactly(code) => format!("[ $exit_code -ne {code} ]"),
+            Self::Range(low, high) => {
+                format!("{{ [ $exit_code -lt {low} ] || [ $exit_code -gt {high} ]; }}")
+            }
+            Self::NonZero => "[ $exit_code -eq 0 ]".to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ExitCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exactly(code) => write!(f, "{code}"),
+            Self::Range(low, high) => write!(f, "{low}-{high}"),
+            Self::NonZero => write!(f, "non-zero"),
+        }
+    }
+}
+
+impl FromStr for ExitCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |code: &str| {
+            code.parse::<i32>()
+                .map_err(|err| format!("invalid exit code '{code}': {err}"))
+        };
+        match s.split_once('-') {
+            Some((low, high)) => {
+                let (low, high) = (parse(low)?, parse(high)?);
+                if low > high {
+                    return Err(format!("empty range of exit codes {s}"));
+                }
+                Ok(Self::Range(low, high))
+            }
+            None => Ok(Self::Exactly(parse(s)?)),
+        }
+    }
+}
+
+impl Command {
+    pub fn new(cmd: &str) -> Self {
+        Self {
+            cmd: cmd.to_string(),
+            long_running: false,
+            expected_output: None,
+            exact_output: false,
+            normalization: Normalization::default(),
+            unexpected_output: None,
+            expected_stderr: None,
+            wait_until: None,
+            wait_until_regex: false,
+            wait_interval: 5,
+            wait_timeout: None,
+            background_delay: None,
+            daemon: false,
+            exit_code: Some(ExitCode::Exactly(0)),
+            expect_diff: None,
+            trace_to: None,
+            trace_append: false,
+            expect_sha256: None,
+            wait_on: WaitOn::Both,
+            expect_no_output_change: false,
+            expect_output_unchanged_across: None,
+            show_output: false,
+            expect_output_empty_lines: None,
+            pipefail: false,
+            record_missing_baseline: false,
+            env: Vec::new(),
+            expect_output_matches_command: None,
+            max_capture_bytes: None,
+            env_file: None,
+            expected_json: None,
+            cache_key: None,
+            retry_on_output: None,
+            retry_attempts: 3,
+            retry: None,
+            retry_delay: 1,
+            timeout: None,
+            stdin_file: None,
+            stdin: None,
+            expect_schema: None,
+            expected_output_file: None,
+            expect_no_file: Vec::new(),
+            expect_files_equal: Vec::new(),
+            asserts: Vec::new(),
+            match_patterns: Vec::new(),
+            output_matches: Vec::new(),
+            group: None,
+            parallel_group: None,
+            capture: None,
+            expand_expected: false,
+            strip_ansi: false,
+            flaky: false,
+            allow_fail: false,
+            ulimits: Vec::new(),
+            expect_signal: None,
+            embed_output: None,
+            isolated: false,
+            script: false,
+            chdir: None,
+            cwd: None,
+            teardown: false,
+            params: None,
+            line: None,
+            file: None,
+            block: None,
+        }
+    }
+
+    /// Runs the command, capturing its output into `$output`.
+    fn capture(&self, options: &ScriptOptions) -> String {
+        // On separate lines, so that a trailing comment in the command
+        // doesn't swallow the parenthesis.
+        match self.max_capture_bytes {
+            // The rest of the output is discarded rather than killing the
+            // command with `SIGPIPE`, which would change its exit code.
+            Some(max_bytes) => format!(
+                "output=$(\n{{\n{}\n}} | {{ head -c {max_bytes}; cat > /dev/null; }}\nexit ${{PIPESTATUS[0]}}\n)",
+                self.invocation(options)
+            ),
+            None => format!("output=$(\n{}\n)", self.invocation(options)),
+        }
+    }
+
+    /// The command as it's run in the script, i.e. with its environment and
+    /// possibly in a container.
+    fn invocation(&self, options: &ScriptOptions) -> String {
+        let invocation = if options.docker.is_some() {
+            // In the working directory of the script, which may have changed
+            // with `bashtestmd:chdir`.
+            format!(
+                r#"docker exec -i -w "$PWD" "$bashtestmd_container" {}"#,
+                self.invocation_in_new_shell(options)
+            )
+        } else if self.isolated || options.isolate || self.timeout().is_some() {
+            // `timeout` runs a program rather than shell code.
+            self.invocation_in_new_shell(options)
+        } else {
+            self.invocation_in_shell()
+        };
+        let invocation = match self.timeout() {
+            Some(timeout) => format!("timeout {timeout} {invocation}"),
+            None => invocation,
+        };
+        // Grouped, so that the whole command reads from the file or text.
+        if let Some(stdin_file) = &self.stdin_file {
+            format!(
+                "{{\n{invocation}\n}} < {}",
+                shell_escape::escape(stdin_file.into())
+            )
+        } else if let Some(stdin) = &self.stdin {
+            format!(
+                "{{\n{invocation}\n}} < <(printf %s {})",
+                shell_escape::escape(stdin.into())
+            )
+        } else {
+            invocation
+        }
+    }
+
+    /// Whether the command runs with `set -o pipefail`, with its tag or with
+    /// `--pipefail`.
+    fn pipefail(&self, options: &ScriptOptions) -> bool {
+        self.pipefail || options.pipefail
+    }
+
+    /// The timeout of the command, which long running commands don't have.
+    fn timeout(&self) -> Option<u64> {
+        self.timeout.filter(|_| !self.long_running)
+    }
+
+    /// The command as it's run by a new `bash` process, e.g. in a container.
+    fn invocation_in_new_shell(&self, options: &ScriptOptions) -> String {
+        let mut script = String::new();
+        // The shell options of the script don't carry over to the new shell.
+        if self.pipefail(options) {
+            script.push_str("set -o pipefail\n");
+        }
+        script.push_str(&self.invocation_in_shell());
+        let script = shell_escape::escape(script.into());
+        if self.env_file.is_some() {
+            // Also define the function in the new shell.
+            format!(r#"bash -c "$(declare -f bashtestmd_load_env_file)"$'\n'{script}"#)
+        } else {
+            format!("bash -c {script}")
+        }
+    }
+
+    /// The command with its environment, as it's run by the shell.
+    fn invocation_in_shell(&self) -> String {
+        // In a subshell, so that redirections and `&` apply to all of the
+        // script, and its `exit` doesn't terminate ours.
+        let cmd = if self.script {
+            format!("(\n{}\n)", self.cmd)
+        } else {
+            self.cmd.clone()
+        };
+        if self.env.is_empty() && self.env_file.is_none() && self.ulimits.is_empty() {
+            return cmd;
+        }
+        // A subshell, so that the variables and limits don't leak into later
+        // commands.
+        let mut invocation = "(\n".to_string();
+        if !self.ulimits.is_empty() {
+            invocation.push_str(&format!("{}\n", self.ulimit_commands()));
+        }
+        if let Some(env_file) = &self.env_file {
+            invocation.push_str(&format!(
+                "bashtestmd_load_env_file {}\n",
+                shell_escape::escape(env_file.into())
+            ));
+        }
+        if !self.env.is_empty() {
+            let exports: Vec<String> = self
+                .env
+                .iter()
+                .map(|(key, value)| format!("{key}={}", shell_escape::escape(value.into())))
+                .collect();
+            invocation.push_str(&format!("export {}\n", exports.join(" ")));
+        }
+        invocation.push_str(&format!("{cmd}\n)"));
+        invocation
+    }
+
+    /// The `ulimit` commands that apply the resource limits of the command.
+    fn ulimit_commands(&self) -> String {
+        let commands: Vec<String> = self
+            .ulimits
+            .iter()
+            .map(|(limit, value)| format!("ulimit -{limit} {}", shell_escape::escape(value.into())))
+            .collect();
+        commands.join("; ")
+    }
+
+    /// Records the Markdown file the command comes from, for the source map
+    /// of the script.
+    pub fn set_file(&mut self, file: &str) {
+        self.file = Some(file.to_string());
+    }
+
+    /// Makes the relative paths in the command's tags relative to `base_dir`.
+    pub fn resolve_paths(&mut self, base_dir: &Path) {
+        let resolve = |path: &mut String| {
+            if Path::new(path).is_relative() {
+                *path = base_dir.join(&path).display().to_string();
+            }
+        };
+        if let Some(path) = &mut self.expect_diff {
+            resolve(path);
+        }
+        if let Some(path) = &mut self.trace_to {
+            resolve(path);
+        }
+        if let Some(path) = &mut self.env_file {
+            resolve(path);
+        }
+        if let Some(path) = &mut self.stdin_file {
+            resolve(path);
+        }
+        if let Some(path) = &mut self.chdir {
+            resolve(path);
+        }
+        if let Some(path) = &mut self.cwd {
+            resolve(path);
+        }
+        if let Some(path) = &mut self.expect_schema {
+            resolve(path);
+        }
+        if let Some(path) = &mut self.expected_output_file {
+            resolve(path);
+        }
+        for path in &mut self.expect_no_file {
+            resolve(path);
+        }
+        for (a, b) in &mut self.expect_files_equal {
+            resolve(a);
+            resolve(b);
+        }
+    }
+
+    /// The position of the code block the output is written into with
+    /// `--embed-output`.
+    pub fn embed_output(&self) -> Option<&Position> {
+        self.embed_output.as_ref()
+    }
+
+    /// Whether the output of the command must be captured into `$output` for
+    /// the checks.
+    fn captures_output(&self) -> bool {
+        self.expected_output.is_some()
+            || self.unexpected_output.is_some()
+            || self.expected_stderr.is_some()
+            || self.expect_diff.is_some()
+            || self.expect_sha256.is_some()
+            || self.expect_no_output_change
+            || self.expect_output_unchanged_across.is_some()
+            || self.expect_output_empty_lines.is_some()
+            || self.expect_output_matches_command.is_some()
+            || self.max_capture_bytes.is_some()
+            || self.expected_json.is_some()
+            || self.retry_on_output.is_some()
+            || self.expect_schema.is_some()
+            || self.expected_output_file.is_some()
+            || self.capture.is_some()
+            || self.embed_output.is_some()
+            || !self.asserts.is_empty()
+            || !self.match_patterns.is_empty()
+            || !self.output_matches.is_empty()
+    }
+
+    /// The shell word that `$expected` is set to for comparing the output
+    /// with `expected_output` or the contents of `expected_output_file`, if
+    /// there's one. A missing file fails the script.
+    fn expected_output_value(
+        &self,
+        options: &ScriptOptions,
+        mut w: impl io::Write,
+    ) -> io::Result<Option<String>> {
+        let expected = if let Some(path) = &self.expected_output_file {
+            let path = shell_escape::escape(path.into());
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    if ! [ -f {0} ]; then
+                        bashtestmd_msg echo "The file with the expected output does not exist: "{0}
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi"#
+                ),
+                path,
+                on_fail = options.on_fail()
+            )?;
+            format!(r#""$(< {path})""#)
+        } else if let Some(output) = &self.expected_output {
+            shell_escape::escape(output.into()).to_string()
+        } else {
+            return Ok(None);
+        };
+        if self.expand_expected {
+            return Ok(Some(format!("$(bashtestmd_expand {expected})")));
+        }
+        Ok(Some(expected))
+    }
+
+    /// Records the TAP test point of the command, for `bashtestmd_fail` to
+    /// report its failure, see `--tap`.
+    fn start_test_point(&self, test: usize, mut w: impl io::Write) -> io::Result<()> {
+        writeln!(
+            w,
+            "bashtestmd_test={test} bashtestmd_test_name={}",
+            shell_escape::escape(self.cmd.replace('\n', " ").into())
+        )
+    }
+
+    /// Reports that the command passed, in the TAP output.
+    fn end_test_point(&self, test: usize, mut w: impl io::Write) -> io::Result<()> {
+        writeln!(
+            w,
+            "echo {}",
+            shell_escape::escape(format!("ok {test} - {}", self.cmd.replace('\n', " ")).into())
+        )
+    }
+
+    fn compile(&self, options: &ScriptOptions, mut w: impl io::Write) -> io::Result<()> {
+        if let Some(chdir) = &self.chdir {
+            // Outside of any subshell, so that it persists.
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    if ! cd {0}; then
+                        bashtestmd_msg echo "Failed to change the working directory to "{0}
+                        bashtestmd_fail $BASHTESTMD_EXIT_COMMAND_FAILED{on_fail}
+                    fi"#
+                ),
+                shell_escape::escape(chdir.into()),
+                on_fail = options.on_fail()
+            )?;
+        }
+        if options.step {
+            // Never pauses in CI, where stdin isn't a terminal.
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    if [ -t 0 ]; then
+                        read -r -p {}
+                    fi"#
+                ),
+                shell_escape::escape(format!("Press enter to run: '{}' ", self.cmd).into())
+            )?;
+        }
+        if !options.timings {
+            return self.compile_quarantined(options, w);
+        }
+        // Long running commands are timed until they're ready.
+        writeln!(w, "bashtestmd_started=$(bashtestmd_now_ms)")?;
+        self.compile_quarantined(options, &mut w)?;
+        writeln!(
+            w,
+            indoc!(
+                r#"
+                bashtestmd_took=$(($(bashtestmd_now_ms) - bashtestmd_started))
+                bashtestmd_msg printf "Took %d.%03ds: '%s'\n" $((bashtestmd_took / 1000)) $((bashtestmd_took % 1000)) {}"#
+            ),
+            shell_escape::escape(self.cmd.as_str().into())
+        )
+    }
+
+    /// Compiles the command, in a subshell whose failures don't terminate the
+    /// script if it's tagged with `bashtestmd:flaky` or
+    /// `bashtestmd:allow-fail`.
+    fn compile_quarantined(
+        &self,
+        options: &ScriptOptions,
+        mut w: impl io::Write,
+    ) -> io::Result<()> {
+        if !self.flaky && !self.allow_fail {
+            return self.compile_in_cwd(options, w);
+        }
+        // In a subshell, where failures only terminate the subshell. Changes
+        // to the state of the shell (e.g. `cd`) don't carry over.
+        writeln!(w, "bashtestmd_flaky_failed=")?;
+        writeln!(w, "(")?;
+        writeln!(w, "bashtestmd_flaky=1")?;
+        self.compile_in_cwd(options, &mut w)?;
+        if self.allow_fail {
+            return writeln!(
+                w,
+                indoc!(
+                    r#"
+                    ) || {{
+                        bashtestmd_flaky_failed=1
+                        bashtestmd_msg echo {}
+                    }}"#
+                ),
+                shell_escape::escape(
+                    format!(
+                        "Warning: '{}' failed, carrying on (allowed to fail)",
+                        self.cmd
+                    )
+                    .into()
+                )
+            );
+        }
+        writeln!(
+            w,
+            indoc!(
+                r#"
+                ) || {{
+                    bashtestmd_flaky_failed=1
+                    bashtestmd_msg echo {}
+                    echo {} >> "$bashtestmd_quarantine_log"
+                }}"#
+            ),
+            shell_escape::escape(format!("FLAKY FAILURE (quarantined): '{}'", self.cmd).into()),
+            shell_escape::escape(self.cmd.as_str().into())
+        )
+    }
+
+    /// Compiles the command, in the directory of its `bashtestmd:cd` tag if it
+    /// has one.
+    fn compile_in_cwd(&self, options: &ScriptOptions, mut w: impl io::Write) -> io::Result<()> {
+        let Some(cwd) = &self.cwd else {
+            return self.compile_retried(options, w);
+        };
+        if options.keep_going {
+            // For going back if a failure skips the `popd`, see `keep_going`.
+            writeln!(w, "bashtestmd_dirs=${{#DIRSTACK[@]}}")?;
+        }
+        writeln!(
+            w,
+            indoc!(
+                r#"
+                if ! pushd {0} > /dev/null; then
+                    bashtestmd_msg echo "Failed to change the working directory to "{0}
+                    bashtestmd_fail $BASHTESTMD_EXIT_COMMAND_FAILED{on_fail}
+                fi"#
+            ),
+            shell_escape::escape(cwd.into()),
+            on_fail = options.on_fail()
+        )?;
+        self.compile_retried(options, &mut w)?;
+        writeln!(w, "popd > /dev/null")
+    }
+
+    /// Compiles the command, running it again until it passes with
+    /// `bashtestmd:retry`.
+    fn compile_retried(&self, options: &ScriptOptions, mut w: impl io::Write) -> io::Result<()> {
+        let Some(attempts) = self.retry.filter(|_| !self.long_running) else {
+            return self.compile_buffered(options, w);
+        };
+        // Each attempt runs in a subshell, which a failed check terminates
+        // with its exit code. Changes to the state of the shell (e.g. `cd`)
+        // don't carry over.
+        writeln!(w, "bashtestmd_attempt=1")?;
+        writeln!(w, "until (")?;
+        self.compile_buffered(options, &mut w)?;
+        writeln!(
+            w,
+            indoc!(
+                r#"
+                ); do
+                    bashtestmd_status=$?
+                    if [ $bashtestmd_attempt -ge {0} ]; then
+                        bashtestmd_msg echo {1}
+                        bashtestmd_fail $bashtestmd_status{on_fail}
+                    fi
+                    bashtestmd_msg echo "Attempt $bashtestmd_attempt of {0} failed, retrying in {2}s"
+                    bashtestmd_attempt=$((bashtestmd_attempt + 1))
+                    sleep {2}
+                done"#
+            ),
+            attempts,
+            shell_escape::escape(
+                format!("'{}' failed after {attempts} attempt(s)", self.cmd).into()
+            ),
+            self.retry_delay,
+            on_fail = options.on_fail()
+        )
+    }
+
+    /// Compiles the command, buffering its output with `--quiet-on-success`.
+    fn compile_buffered(&self, options: &ScriptOptions, mut w: impl io::Write) -> io::Result<()> {
+        if !options.quiet_on_success {
+            return self.compile_unbuffered(options, w);
+        }
+        // Failures print the buffered output in `bashtestmd_fail`.
+        writeln!(w, "bashtestmd_quiet_start")?;
+        self.compile_unbuffered(options, &mut w)?;
+        writeln!(w, "bashtestmd_quiet_end")
+    }
+
+    fn compile_unbuffered(&self, options: &ScriptOptions, mut w: impl io::Write) -> io::Result<()> {
+        let Some(cache_key) = self.cache_key.as_ref().filter(|_| !self.long_running) else {
+            return self.compile_uncached(options, w);
+        };
+
+        let marker = Path::new(&options.cache_dir).join(cache_key);
+        writeln!(
+            w,
+            indoc!(
+                r#"
+                bashtestmd_cache_marker={}
+                if [ -f "$bashtestmd_cache_marker" ]; then
+                    bashtestmd_msg echo {}
+                else"#
+            ),
+            shell_escape::escape(marker.display().to_string().into()),
+            shell_escape::escape(
+                format!("Skipping (cached as '{cache_key}'): '{}'", self.cmd).into()
+            )
+        )?;
+        self.compile_uncached(options, &mut w)?;
+        // Only reached if all checks passed.
+        writeln!(
+            w,
+            indoc!(
+                r#"
+                mkdir -p "$(dirname "$bashtestmd_cache_marker")"
+                touch "$bashtestmd_cache_marker"
+                fi"#
+            )
+        )
+    }
+
+    fn compile_uncached(&self, options: &ScriptOptions, mut w: impl io::Write) -> io::Result<()> {
+        // The command is an argument of `printf` rather than part of its
+        // format, so that it's printed as it is, whatever quotes, `$` or `%`
+        // it contains.
+        match &self.params {
+            Some(params) => writeln!(
+                w,
+                r#"bashtestmd_msg printf "Running: '%s' (%s)\n" {} {}"#,
+                shell_escape::escape(self.cmd.as_str().into()),
+                shell_escape::escape(params.into())
+            )?,
+            None => writeln!(
+                w,
+                r#"bashtestmd_msg printf "Running: '%s'\n" {}"#,
+                shell_escape::escape(self.cmd.as_str().into())
+            )?,
+        }
+
+        if let Some(env_file) = &self.env_file {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    if ! [ -f {0} ]; then
+                        bashtestmd_msg echo "Environment file "{0}" does not exist"
+                        bashtestmd_fail $BASHTESTMD_EXIT_COMMAND_FAILED{on_fail}
+                    fi"#
+                ),
+                shell_escape::escape(env_file.into()),
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        if let Some(stdin_file) = &self.stdin_file {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    if ! [ -f {0} ]; then
+                        bashtestmd_msg echo "Standard input file "{0}" does not exist"
+                        bashtestmd_fail $BASHTESTMD_EXIT_COMMAND_FAILED{on_fail}
+                    fi"#
+                ),
+                shell_escape::escape(stdin_file.into()),
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        if self.long_running {
+            if let Some(wait_until) = &self.wait_until {
+                // `$output` always gets both streams, while the text is
+                // searched for in `$wait_output` if only one of them matters.
+                let (deadline, check_deadline) = match self.wait_timeout {
+                    // `$SECONDS` counts from the start of the script.
+                    Some(timeout) => (
+                        format!("bashtestmd_wait_deadline=$((SECONDS + {timeout}))\n"),
+                        format!(
+                            indoc!(
+                                r#"
+                                  if [ $SECONDS -ge $bashtestmd_wait_deadline ]; then
+                                    {}
+                                    bashtestmd_msg echo {}
+                                    check_and_output_long_running_output
+                                    bashtestmd_fail $BASHTESTMD_EXIT_TIMEOUT{on_fail}
+                                  fi
+                                "#
+                            ),
+                            // Ends the line of the progress, if there's one.
+                            match options.wait_progress.on_done() {
+                                "" => ":",
+                                on_done => on_done,
+                            },
+                            shell_escape::escape(
+                                format!("'{wait_until}' not found in the output after {timeout}s")
+                                    .into()
+                            ),
+                            on_fail = options.on_fail()
+                        ),
+                    ),
+                    None => (String::new(), String::new()),
+                };
+                let (run_in_background, wait_file) = match self.wait_on {
+                    WaitOn::Both => (format!("{} &> $output &", self.invocation(options)), "$output"),
+                    WaitOn::Stdout => (
+                        format!(
+                            "wait_output=$(mktemp)\nbashtestmd_temp_files+=($wait_output)\n{} > >(tee $wait_output >> $output) 2>> $output &",
+                            self.invocation(options)
+                        ),
+                        "$wait_output",
+                    ),
+                    WaitOn::Stderr => (
+                        format!(
+                            "wait_output=$(mktemp)\nbashtestmd_temp_files+=($wait_output)\n{} 2> >(tee $wait_output >> $output) >> $output &",
+                            self.invocation(options)
+                        ),
+                        "$wait_output",
+                    ),
+                };
+                writeln!(
+                    w,
+                    indoc!(
+                        r#"
+                        output=$(mktemp)
+                        bashtestmd_temp_files+=($output)
+                        export BASHTESTMD_LONG_RUNNING_OUTPUT=$output
+                        {}
+                        background_process_pid=$!
+                        bashtestmd_msg echo "Waiting for process with PID: $background_process_pid to have a match in $output"
+                        {}until grep -q -i {} {} {}
+                        do       
+                          if ! ps $background_process_pid > /dev/null 
+                          then
+                            echo "The background process died, output:" >&2
+                            bashtestmd_msg cat $output
+                            bashtestmd_fail $BASHTESTMD_EXIT_READINESS_FAILED{on_fail}
+                          fi
+                        {}  {}
+                          sleep {}
+                        done
+                        {}
+                        "#
+                    ),
+                    run_in_background,
+                    deadline,
+                    // The text is literal, unless it's tagged as a pattern.
+                    if self.wait_until_regex { "-E" } else { "-F" },
+                    shell_escape::escape(wait_until.into()),
+                    wait_file,
+                    check_deadline,
+                    options.wait_progress.on_poll(),
+                    self.wait_interval,
+                    options.wait_progress.on_done(),
+                    on_fail = options.on_fail()
+                )?;
+                if options.dump_long_running_output {
+                    writeln!(w, "check_and_output_long_running_output")?;
+                }
+            } else if self.daemon {
+                // Only checks that the process didn't exit right away, later
+                // commands wait for whatever they need themselves.
+                let grace_period = self.background_delay.unwrap_or(DAEMON_GRACE_PERIOD);
+                writeln!(
+                    w,
+                    indoc!(
+                        r#"
+                        output=$(mktemp)
+                        bashtestmd_temp_files+=($output)
+                        export BASHTESTMD_LONG_RUNNING_OUTPUT=$output
+                        {} &> $output &
+                        background_process_pid=$!
+                        sleep {}
+                        if ! ps $background_process_pid > /dev/null; then
+                            bashtestmd_msg echo "The background process exited within {}s, output:"
+                            bashtestmd_msg cat $output
+                            bashtestmd_fail $BASHTESTMD_EXIT_READINESS_FAILED{on_fail}
+                        fi"#
+                    ),
+                    self.invocation(options),
+                    grace_period,
+                    grace_period,
+                    on_fail = options.on_fail()
+                )?;
+                if options.dump_long_running_output {
+                    writeln!(w, "check_and_output_long_running_output")?;
+                }
+            } else {
+                // No expected output, just run the command and wait for a
+                // while. Very, very hackish.
+                writeln!(w, "{} &", self.invocation(options))?;
+                writeln!(
+                    w,
+                    "sleep {}",
+                    self.background_delay
+                        .unwrap_or(options.default_background_delay)
+                )?;
+            }
+            return Ok(());
+        }
+
+        if let Some(trace_to) = &self.trace_to {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    bashtestmd_trace_file={}
+                    exec {{bashtestmd_trace_fd}}{}"$bashtestmd_trace_file"
+                    BASH_XTRACEFD=$bashtestmd_trace_fd
+                    set -x"#
+                ),
+                shell_escape::escape(trace_to.into()),
+                if self.trace_append { ">>" } else { ">" }
+            )?;
+        }
+
+        // With `--pipefail`, the option is already on for the whole script.
+        let toggle_pipefail = self.pipefail && !options.pipefail;
+        if toggle_pipefail {
+            // Rather than a subshell, so the command can still change the
+            // state of the shell for later commands.
+            writeln!(w, "set -o pipefail")?;
+        }
+        if let Some(pattern) = &self.retry_on_output {
+            // Only non-zero exit codes are failures if the exit code is ignored.
+            let failed = self.exit_code.unwrap_or(ExitCode::Exactly(0)).mismatch();
+            // Stderr goes to a file so that it can be searched too, and is
+            // printed afterwards.
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    bashtestmd_stderr=$(mktemp)
+                    bashtestmd_temp_files+=($bashtestmd_stderr)
+                    bashtestmd_attempt=1
+                    while true; do
+                    {{
+                    {}
+                    }} 2> $bashtestmd_stderr
+                    exit_code=$?
+                    cat $bashtestmd_stderr >&2
+                    if {} && [ $bashtestmd_attempt -lt {} ] && grep -q -i -E {} - $bashtestmd_stderr <<< "$output"; then
+                        bashtestmd_msg echo {} "(attempt $bashtestmd_attempt of {})"
+                        bashtestmd_attempt=$((bashtestmd_attempt + 1))
+                        sleep {}
+                    else
+                        break
+                    fi
+                    done"#
+                ),
+                self.capture(options),
+                failed,
+                self.retry_attempts,
+                shell_escape::escape(pattern.into()),
+                shell_escape::escape(
+                    format!("The output matches the transient error '{pattern}', retrying").into()
+                ),
+                self.retry_attempts,
+                self.retry_delay
+            )?;
+            if self.expected_stderr.is_some() {
+                writeln!(w, "stderr=$(< $bashtestmd_stderr)")?;
+            }
+        } else {
+            if self.expected_stderr.is_some() {
+                // Into a file, since `$(...)` can only capture one stream.
+                writeln!(
+                    w,
+                    indoc!(
+                        r#"
+                        bashtestmd_stderr=$(mktemp)
+                        bashtestmd_temp_files+=($bashtestmd_stderr)
+                        {{
+                        {}
+                        }} 2> $bashtestmd_stderr"#
+                    ),
+                    self.capture(options)
+                )?;
+            } else if self.captures_output() {
+                writeln!(w, "{}", self.capture(options))?;
+            } else {
+                writeln!(w, "{}", self.invocation(options))?;
+            }
+            // Save the exit code right away, before any of the checks below
+            // clobber `$?`. Even with `bashtestmd:exit-code-ignore`, where a
+            // timeout or `bashtestmd:assert` may still look at it.
+            writeln!(w, "exit_code=$?")?;
+            if self.expected_stderr.is_some() {
+                writeln!(w, "stderr=$(< $bashtestmd_stderr)")?;
+            }
+        }
+        if toggle_pipefail {
+            writeln!(w, "set +o pipefail")?;
+        }
+        if self.strip_ansi && self.captures_output() {
+            // E.g. `\e[31m`, the expected output in the Markdown file is plain
+            // text.
+            let strip = r#"sed $'s/\e\\[[0-9;?]*[A-Za-z]//g'"#;
+            writeln!(w, r#"output=$(printf '%s' "$output" | {strip})"#)?;
+            if self.expected_stderr.is_some() {
+                writeln!(w, r#"stderr=$(printf '%s' "$stderr" | {strip})"#)?;
+            }
+        }
+        if let Some(timeout) = self.timeout() {
+            // `timeout` exits with 124 if it terminated the command, whatever
+            // exit code the command is expected to have.
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    if [ $exit_code -eq 124 ]; then
+                        bashtestmd_msg echo {}
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_TIMEOUT{on_fail}
+                    fi"#
+                ),
+                shell_escape::escape(format!("'{}' timed out after {timeout}s", self.cmd).into()),
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        if let Some(variable) = &self.capture {
+            writeln!(w, "{variable}=$output")?;
+        }
+        if let Some(position) = &self.embed_output {
+            writeln!(
+                w,
+                r#"printf '%s\n' "$output" > "$BASHTESTMD_EMBED_DIR/{}""#,
+                position.start.offset
+            )?;
+        }
+
+        if self.trace_to.is_some() {
+            // Unsetting `BASH_XTRACEFD` also closes the trace file.
+            writeln!(w, "set +x")?;
+            writeln!(w, "unset BASH_XTRACEFD")?;
+        }
+
+        if let Some(output) = self.expected_output.as_ref().filter(|_| self.exact_output) {
+            // Command substitution strips the trailing newlines of the output.
+            let output = output.trim_end_matches('\n');
+            let expected = if self.expand_expected {
+                format!(
+                    "$(bashtestmd_expand {})",
+                    shell_escape::escape(output.into())
+                )
+            } else {
+                shell_escape::escape(output.into()).to_string()
+            };
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    expected={}
+                    if [ "$output" != "$expected" ]; then
+                        bashtestmd_msg echo "The output is not exactly the expected output:"
+                        bashtestmd_msg diff {} <(printf '%s\n' "$expected") <(printf '%s\n' "$output")
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                expected,
+                match options.mismatch_context {
+                    Some(context) => format!("-U {context}"),
+                    None => "-u".to_string(),
+                },
+                on_fail = options.on_fail()
+            )?;
+        } else if let Some(expected) = self.expected_output_value(options, &mut w)? {
+            let report_mismatch = match options.mismatch_context {
+                // Only show the first hunk of the diff between the two.
+                Some(context) => format!(
+                    concat!(
+                        "    bashtestmd_msg echo \"Expected output not found in text, first difference:\"\n",
+                        "    diff -U {} <(printf '%s\\n' \"$expected\") <(printf '%s\\n' \"$output\") ",
+                        "| bashtestmd_msg awk '/^@@/ {{ hunks++ }} hunks <= 1'"
+                    ),
+                    context
+                ),
+                None => concat!(
+                    "    bashtestmd_msg echo \"'$expected' not found in text:\"\n",
+                    "    bashtestmd_msg echo \"'$output'\""
+                )
+                .to_string(),
+            };
+            writeln!(w, "expected={expected}")?;
+            // Whitespace at the ends of the lines and blank lines at the end,
+            // which command substitution strips, don't count, unlike with
+            // `bashtestmd:compare-output-exact`. The messages show the output
+            // and the expected output as they are.
+            let filter = match self.normalization.filter() {
+                Some(filter) => format!("{TRIM_LINES} | {filter}"),
+                None => TRIM_LINES.to_string(),
+            };
+            writeln!(
+                w,
+                r#"normalized_output=$(printf '%s\n' "$output" | {filter})"#
+            )?;
+            writeln!(
+                w,
+                r#"normalized_expected=$(printf '%s\n' "$expected" | {filter})"#
+            )?;
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    # Either of the two must be a substring of the other. This kinda protects us
+                    # against whitespace differences, trimming, etc.
+                    if ! [[ $normalized_output == *"$normalized_expected"* || $normalized_expected == *"$normalized_output"* ]]; then
+                    {}
+                        check_and_output_long_running_output
+                        bashtestmd_msg echo "=========== END OF THE LONG RUNNING OUTPUT. Terminating..."
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                report_mismatch,
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        if let Some(expected_stderr) = &self.expected_stderr {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    expected_stderr={}
+                    if ! [[ $stderr == *"$expected_stderr"* || $expected_stderr == *"$stderr"* ]]; then
+                        bashtestmd_msg echo "'$expected_stderr' not found in stderr:"
+                        bashtestmd_msg echo "'$stderr'"
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                shell_escape::escape(expected_stderr.into()),
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        if let Some(unexpected) = &self.unexpected_output {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    unexpected={}
+                    if [[ $output == *"$unexpected"* ]]; then
+                        bashtestmd_msg echo "Unexpected '$unexpected' found in output:"
+                        bashtestmd_msg echo "'$output'"
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                shell_escape::escape(unexpected.into()),
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        for (name, pattern) in &self.match_patterns {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    if ! grep -q -E {} <<< "$output"; then
+                        bashtestmd_msg echo "The output doesn't contain a valid {}:"
+                        bashtestmd_msg echo "'$output'"
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                shell_escape::escape((*pattern).into()),
+                name,
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        for pattern in &self.output_matches {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    if ! grep -q -E {} <<< "$output"; then
+                        bashtestmd_msg echo {}
+                        bashtestmd_msg echo "'$output'"
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                shell_escape::escape(pattern.into()),
+                shell_escape::escape(
+                    format!("No line of the output matches the pattern '{pattern}':").into()
+                ),
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        if let Some(expected_json) = &self.expected_json {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    if ! command -v jq > /dev/null; then
+                        bashtestmd_msg echo "jq is required to compare JSON output, but it's not installed"
+                        bashtestmd_fail $BASHTESTMD_EXIT_COMMAND_FAILED{on_fail}
+                    fi
+                    expected_json={}
+                    # Sorting the keys makes the comparison independent of key order and formatting.
+                    if ! actual_json=$(jq -S . <<< "$output"); then
+                        bashtestmd_msg echo "The output is not valid JSON:"
+                        bashtestmd_msg echo "'$output'"
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    elif [ "$actual_json" != "$(jq -S . <<< "$expected_json")" ]; then
+                        bashtestmd_msg echo "The output is not the expected JSON:"
+                        bashtestmd_msg diff -u <(jq -S . <<< "$expected_json") <(printf '%s\n' "$actual_json")
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                shell_escape::escape(expected_json.into()),
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        if let Some(schema) = &self.expect_schema {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    schema={}
+                    if ! [ -f "$schema" ]; then
+                        bashtestmd_msg echo "Schema file '$schema' does not exist"
+                        bashtestmd_fail $BASHTESTMD_EXIT_COMMAND_FAILED{on_fail}
+                    fi
+                    instance=$(mktemp)
+                    bashtestmd_temp_files+=($instance)
+                    printf '%s\n' "$output" > "$instance"
+                    if command -v check-jsonschema > /dev/null; then
+                        schema_validator=(check-jsonschema --schemafile "$schema" "$instance")
+                    elif command -v ajv > /dev/null; then
+                        schema_validator=(ajv validate -s "$schema" -d "$instance")
+                    elif command -v jsonschema > /dev/null; then
+                        schema_validator=(jsonschema -i "$instance" "$schema")
+                    else
+                        bashtestmd_msg echo "A JSON Schema validator (check-jsonschema, ajv or jsonschema) is required to check the output against '$schema', but none is installed"
+                        bashtestmd_fail $BASHTESTMD_EXIT_COMMAND_FAILED{on_fail}
+                    fi
+                    if ! schema_errors=$("${{schema_validator[@]}}" 2>&1); then
+                        bashtestmd_msg echo "The output is not valid against the schema '$schema':"
+                        bashtestmd_msg printf '%s\n' "$schema_errors"
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                shell_escape::escape(schema.into()),
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        if let Some(baseline) = &self.expect_diff {
+            let missing_baseline = if self.record_missing_baseline {
+                indoc!(
+                    r#"
+                        mkdir -p "$(dirname "$baseline")"
+                        printf '%s\n' "$output" > "$baseline"
+                        bashtestmd_msg echo "Recorded the output of the command as the baseline in '$baseline'"
+                    "#
+                )
+            } else {
+                indoc!(
+                    r#"
+                        bashtestmd_msg echo "Baseline file '$baseline' does not exist."
+                        bashtestmd_msg echo "Generate it by saving the output of the command to '$baseline', then run again."
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    "#
+                )
+            };
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    baseline={}
+                    if ! [ -f "$baseline" ]; then
+                    {}elif ! bashtestmd_msg diff -u "$baseline" <(printf '%s\n' "$output"); then
+                        bashtestmd_msg echo "Output differs from baseline '$baseline' (see the diff above)"
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                shell_escape::escape(baseline.into()),
+                missing_baseline,
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        if let Some(hash) = &self.expect_sha256 {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    if command -v sha256sum > /dev/null; then
+                        actual_hash=$(printf '%s\n' "$output" | sha256sum | cut -d ' ' -f 1)
+                    else
+                        actual_hash=$(printf '%s\n' "$output" | shasum -a 256 | cut -d ' ' -f 1)
+                    fi
+                    expected_hash={}
+                    if [ "$actual_hash" != "$expected_hash" ]; then
+                        bashtestmd_msg echo "Expected output with SHA-256 hash $expected_hash, got $actual_hash"
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                shell_escape::escape(hash.to_lowercase().into()),
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        if let Some(other_cmd) = &self.expect_output_matches_command {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    other_output=$(
+                    {}
+                    )
+                    if [ "$output" != "$other_output" ]; then
+                        bashtestmd_msg echo "The output doesn't match the output of:" {}
+                        bashtestmd_msg diff -u <(printf '%s\n' "$other_output") <(printf '%s\n' "$output")
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                other_cmd,
+                shell_escape::escape(other_cmd.into()),
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        if let Some(empty_lines) = self.expect_output_empty_lines {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    empty_lines=$(printf '%s\n' "$output" | grep -c '^$')
+                    if [ $empty_lines -ne {0} ]; then
+                        bashtestmd_msg echo "Expected {0} empty lines in the output, found $empty_lines"
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                empty_lines,
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        if let Some(exit_code) = self.exit_code {
+            // The command may have failed because it hit one of the limits.
+            let report_limits = if self.ulimits.is_empty() {
+                String::new()
+            } else {
+                format!(
+                    "    bashtestmd_msg echo {}\n",
+                    shell_escape::escape(
+                        format!(
+                            "The command ran with the limits: {}",
+                            self.ulimit_commands()
+                        )
+                        .into()
+                    )
+                )
+            };
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    if {3}; then
+                        bashtestmd_msg echo "Expected exit code {0}{2}, got $exit_code"
+                    {1}    check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_COMMAND_FAILED{on_fail}
+                    fi
+                    "#,
+                ),
+                exit_code,
+                report_limits,
+                self.expect_signal
+                    .map(|signal| format!(" (terminated by SIG{signal})"))
+                    .unwrap_or_default(),
+                exit_code.mismatch(),
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        for path in &self.expect_no_file {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    if [ -e {0} ] || [ -L {0} ]; then
+                        bashtestmd_msg echo "Expected "{0}" to not exist after the command, but it does"
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_COMMAND_FAILED{on_fail}
+                    fi
+                    "#
+                ),
+                shell_escape::escape(path.into()),
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        for (a, b) in &self.expect_files_equal {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    for file in {0} {1}; do
+                        if ! [ -f "$file" ]; then
+                            bashtestmd_msg echo "Expected '$file' to be equal to another file, but it does not exist"
+                            check_and_output_long_running_output
+                            bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                        fi
+                    done
+                    if ! cmp -s {0} {1}; then
+                        bashtestmd_msg echo "Files "{0}" and "{1}" differ:"
+                        bashtestmd_msg diff -u {0} {1}
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                shell_escape::escape(a.into()),
+                shell_escape::escape(b.into()),
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        for expr in &self.asserts {
+            // In a subshell, so that the expression can't change the state of
+            // the script.
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    if ! ( {} ); then
+                        bashtestmd_msg echo {}
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                expr,
+                shell_escape::escape(format!("Assertion failed: {expr}").into()),
+                on_fail = options.on_fail()
+            )?;
+        }
+
+        if self.expect_no_output_change {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    first_output=$output
+                    bashtestmd_msg echo "Running the command again, its output must not change"
+                    {}
+                    exit_code=$?
+                    if [ "$output" != "$first_output" ]; then
+                        bashtestmd_msg echo "The output changed when running the command again:"
+                        bashtestmd_msg diff -u <(printf '%s\n' "$first_output") <(printf '%s\n' "$output")
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                    fi
+                    "#
+                ),
+                self.capture(options),
+                on_fail = options.on_fail()
+            )?;
+            if let Some(exit_code) = self.exit_code {
+                writeln!(
+                    w,
+                    indoc!(
+                        r#"
+                        if {1}; then
+                            bashtestmd_msg echo "Expected exit code {0} when running the command again, got $exit_code"
+                            check_and_output_long_running_output
+                            bashtestmd_fail $BASHTESTMD_EXIT_COMMAND_FAILED{on_fail}
+                        fi
+                        "#,
+                    ),
+                    exit_code,
+                    exit_code.mismatch(),
+                    on_fail = options.on_fail()
+                )?;
+            }
+        }
+
+        if let Some(runs) = self.expect_output_unchanged_across {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    first_output=$output
+                    for bashtestmd_run in $(seq 2 {0}); do
+                        sleep 1
+                        bashtestmd_msg echo "Running the command again ($bashtestmd_run of {0}), its output must not change"
+                        {1}
+                        exit_code=$?
+                        if [ "$output" != "$first_output" ]; then
+                            bashtestmd_msg echo "The output of run $bashtestmd_run differs from the output of run 1:"
+                            bashtestmd_msg diff -u <(printf '%s\n' "$first_output") <(printf '%s\n' "$output")
+                            check_and_output_long_running_output
+                            bashtestmd_fail $BASHTESTMD_EXIT_OUTPUT_MISMATCH{on_fail}
+                        fi
+                    "#
+                ),
+                runs,
+                self.capture(options),
+                on_fail = options.on_fail()
+            )?;
+            if let Some(exit_code) = self.exit_code {
+                writeln!(
+                    w,
+                    indoc!(
+                        r#"
+                        if {1}; then
+                            bashtestmd_msg echo "Expected exit code {0} in run $bashtestmd_run, got $exit_code"
+                            check_and_output_long_running_output
+                            bashtestmd_fail $BASHTESTMD_EXIT_COMMAND_FAILED{on_fail}
+                        fi
+                        "#,
+                    ),
+                    exit_code,
+                    exit_code.mismatch(),
+                    on_fail = options.on_fail()
+                )?;
+            }
+            writeln!(w, "done")?;
+        }
+
+        // Commands that don't capture their output already print it.
+        if self.show_output && self.captures_output() {
+            writeln!(
+                w,
+                indoc!(
+                    r#"
+                    bashtestmd_msg echo "Output of the command:"
+                    printf '%s\n' "$output" | sed 's/^/> /'
+                    "#
+                )
+            )?;
+        }
+
+        if self.trace_to.is_some() {
+            writeln!(w, "bashtestmd_trace_file=")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Options that apply to the generated script as a whole, rather than to
+/// individual commands.
+pub struct ScriptOptions {
+    /// Print the paths of the temporary files created by the script when it
+    /// exits.
+    pub preserve_temp: bool,
+    /// Only show this many lines of context around the first difference when
+    /// the command output doesn't match the expected output.
+    pub mismatch_context: Option<usize>,
+    /// Run `before`, then all commands, then `after` in a subshell with its
+    /// own `EXIT` trap, so that `after` runs no matter what.
+    pub atomic: bool,
+    pub before: Vec<Command>,
+    pub after: Vec<Command>,
+    /// Run the commands in a container created from this image.
+    pub docker: Option<String>,
+    /// The directory the script changes to before all commands, so that they
+    /// don't depend on where it's run from.
+    pub working_dir: Option<String>,
+    /// Directory with the markers of the commands with a `cache-key` that
+    /// already succeeded.
+    pub cache_dir: String,
+    /// Buffer the output of each command, and only print it if the command
+    /// fails.
+    pub quiet_on_success: bool,
+    /// How waiting for long running commands is shown.
+    pub wait_progress: WaitProgress,
+    /// Abort before the next command once the script ran for this many
+    /// seconds.
+    pub max_total_duration: Option<u64>,
+    /// Terminate the running command and the script once the script ran for
+    /// this many seconds.
+    pub max_runtime: Option<u64>,
+    /// Wait for a keypress before each command if the script runs in a
+    /// terminal.
+    pub step: bool,
+    /// Run every command in a new `bash` process.
+    pub isolate: bool,
+    /// Where the script writes its own messages.
+    pub messages_to: MessagesTo,
+    /// Copy all output of the script to this file.
+    pub log_file: Option<String>,
+    /// Seconds to wait after starting a long running command without
+    /// `wait-until`.
+    pub default_background_delay: u64,
+    /// Carry on with the next command after a failure, and report the number
+    /// of failed commands at the end. Not supported in `atomic` mode.
+    pub keep_going: bool,
+    /// Set `set -o pipefail` in the header of the script, so that every
+    /// command runs with it, as if it was tagged with `bashtestmd:pipefail`.
+    pub pipefail: bool,
+    /// The interpreter of the shebang line, e.g. `/bin/bash`. The script uses
+    /// bash features, so it must be a bash-compatible shell.
+    pub shell: String,
+    /// Print the results in the Test Anything Protocol, with a test point per
+    /// command. Not supported in `atomic` mode.
+    pub tap: bool,
+    /// Also print the output of a long running command when it's ready, not
+    /// only when something fails.
+    pub dump_long_running_output: bool,
+    /// The message printed when all the commands passed, or nothing if
+    /// `None`.
+    pub success_message: Option<String>,
+    /// Print how long each command took.
+    pub timings: bool,
+}
+
+impl ScriptOptions {
+    /// What follows the calls of `bashtestmd_fail` in the code of a command.
+    /// With `--keep-going`, a failure breaks out of the loops of the command
+    /// and of the one around it, see `keep_going`.
+    fn on_fail(&self) -> &'static str {
+        if self.keep_going {
+            " || break 99"
+        } else {
+            ""
+        }
+    }
+}
+
+impl Default for ScriptOptions {
+    fn default() -> Self {
+        Self {
+            preserve_temp: false,
+            mismatch_context: None,
+            atomic: false,
+            before: Vec::new(),
+            after: Vec::new(),
+            docker: None,
+            working_dir: None,
+            cache_dir: ".bashtestmd-cache".to_string(),
+            quiet_on_success: false,
+            wait_progress: WaitProgress::default(),
+            max_total_duration: None,
+            max_runtime: None,
+            step: false,
+            isolate: false,
+            messages_to: MessagesTo::default(),
+            log_file: None,
+            default_background_delay: 120,
+            keep_going: false,
+            pipefail: false,
+            shell: "/usr/bin/env bash".to_string(),
+            tap: false,
+            dump_long_running_output: false,
+            success_message: Some("All tests passed!".to_string()),
+            timings: false,
+        }
+    }
+}
+
+/// Where a command starts in the generated script.
+pub struct SourceMapEntry {
+    /// The line in the generated script, starting at 1.
+    pub script_line: usize,
+    /// The line in the Markdown file, if the command comes from it.
+    pub markdown_line: Option<usize>,
+    /// The Markdown file, if it was recorded with `Command::set_file`.
+    pub markdown_file: Option<String>,
+    pub cmd: String,
+}
+
+pub fn compile_commands_into_bash(
+    cmds: Vec<Command>,
+    options: &ScriptOptions,
+) -> (String, Vec<SourceMapEntry>) {
+    let mut script = Vec::<u8>::new();
+    let mut source_map = Vec::new();
+    let (teardown, cmds): (Vec<Command>, Vec<Command>) =
+        cmds.into_iter().partition(|cmd| cmd.teardown);
+    // Shebang.
+    writeln!(&mut script, "#!{}", options.shell).unwrap();
+    if let Some(log_file) = &options.log_file {
+        // Before anything else, so that the log is complete.
+        writeln!(
+            &mut script,
+            "exec > >(tee {}) 2>&1",
+            shell_escape::escape(log_file.into())
+        )
+        .unwrap();
+    }
+    if options.pipefail {
+        // The checks of the script don't pipe from commands that may fail, so
+        // the option is on for all of it.
+        writeln!(&mut script, "set -o pipefail").unwrap();
+    }
+    // Also if a command exits the script.
+    let show_quiet_output = if options.quiet_on_success {
+        "bashtestmd_quiet_end show; "
+    } else {
+        ""
+    };
+    // Aborts the script even with `--keep-going`, after a failure that isn't
+    // of a single command.
+    let abort = if options.keep_going {
+        "\n    bashtestmd_final_exit"
+    } else {
+        ""
+    };
+    // Before the background processes and the container are gone.
+    let run_teardown = if teardown.is_empty() {
+        ""
+    } else {
+        "bashtestmd_teardown; "
+    };
+    if options.docker.is_some() {
+        writeln!(
+            &mut script,
+            r#"trap '{show_quiet_output}{run_teardown}jobs -p | xargs -r kill 2> /dev/null; docker rm -f "$bashtestmd_container" &> /dev/null' EXIT"#
+        )
+        .unwrap();
+    } else {
+        writeln!(
+            &mut script,
+            r#"trap '{show_quiet_output}{run_teardown}jobs -p | xargs -r kill 2> /dev/null' EXIT"#
+        )
+        .unwrap();
+    }
+    let messages_redirect = match options.messages_to {
+        MessagesTo::Stdout => "",
+        MessagesTo::Stderr => " >&2",
+    };
+    if options.tap {
+        writeln!(
+            &mut script,
+            indoc!(
+                r#"
+                # Runs a command that prints messages of bashtestmd itself, rather
+                # than output of the commands under test, see `--messages-to`.
+                # They are comments of the TAP output.
+                bashtestmd_msg() {{
+                    "$@" | sed 's/^/# /'{}
+                    return ${{PIPESTATUS[0]}}
+                }}
+
+                # The subshell whose failures are reported as test points.
+                bashtestmd_tap_shell=0
+                "#
+            ),
+            messages_redirect
+        )
+        .unwrap();
+    } else {
+        writeln!(
+            &mut script,
+            indoc!(
+                r#"
+                # Runs a command that prints messages of bashtestmd itself, rather
+                # than output of the commands under test, see `--messages-to`.
+                bashtestmd_msg() {{
+                    "$@"{}
+                }}
+                "#
+            ),
+            messages_redirect
+        )
+        .unwrap();
+    }
+    writeln!(
+        &mut script,
+        indoc!(
+        r#"
+        check_and_output_long_running_output() {{
+            if [[ -n "$BASHTESTMD_LONG_RUNNING_OUTPUT" && -f "$BASHTESTMD_LONG_RUNNING_OUTPUT" ]]; then
+                bashtestmd_msg echo "Output of the long running task:"
+                bashtestmd_msg cat "$BASHTESTMD_LONG_RUNNING_OUTPUT"
+            fi
+        }}
+
+        # Exit codes of the script, depending on what failed first.
+        BASHTESTMD_EXIT_COMMAND_FAILED=1
+        BASHTESTMD_EXIT_OUTPUT_MISMATCH=2
+        BASHTESTMD_EXIT_TIMEOUT=3
+        BASHTESTMD_EXIT_READINESS_FAILED=4
+
+        bashtestmd_failures=0
+        bashtestmd_exit_code=0
+
+        # Records a failed check, given the exit code for its kind of failure.
+        # Unless it runs with `--keep-going`, the script is fail-fast, so this
+        # terminates it.
+        bashtestmd_fail() {{
+            bashtestmd_quiet_end show
+            if [[ -n "$bashtestmd_trace_file" ]]; then
+                bashtestmd_msg echo "Shell trace of the failed command: $bashtestmd_trace_file"
+            fi
+            # Failures of commands tagged with `bashtestmd:flaky` or
+            # `bashtestmd:allow-fail` don't count.
+            if [[ -n "$bashtestmd_flaky" ]]; then
+                exit $1
+            fi
+            bashtestmd_failures=$((bashtestmd_failures + 1))
+            if [ $bashtestmd_exit_code -eq 0 ]; then
+                bashtestmd_exit_code=$1
+            fi
+        {}    {}
+        }}
+
+        # Exports the variables from a dotenv-style file of `KEY=VALUE` lines,
+        # without running it as a script.
+        bashtestmd_load_env_file() {{
+            local line key value
+            while IFS= read -r line || [ -n "$line" ]; do
+                line=${{line%$'\r'}}
+                if [[ $line =~ ^[[:space:]]*(#|$) ]]; then
+                    continue
+                fi
+                line=${{line#export }}
+                key=${{line%%=*}}
+                value=${{line#*=}}
+                if ! [[ $line == *=* && $key =~ ^[A-Za-z_][A-Za-z0-9_]*$ ]]; then
+                    echo "Ignoring invalid line in environment file $1: $line" >&2
+                    continue
+                fi
+                if [[ $value =~ ^\"(.*)\"$ || $value =~ ^\'(.*)\'$ ]]; then
+                    value=${{BASH_REMATCH[1]}}
+                fi
+                export "$key=$value"
+            done < "$1"
+        }}
+
+        # Prints the argument with its `$NAME` and `${{NAME}}` variables
+        # expanded, leaving unset ones as they are. Unlike `eval`, this doesn't
+        # run any commands in the text.
+        bashtestmd_expand() {{
+            local text=$1 expanded= name
+            while [[ $text =~ \$(\{{([A-Za-z_][A-Za-z0-9_]*)\}}|([A-Za-z_][A-Za-z0-9_]*)) ]]; do
+                name=${{BASH_REMATCH[2]}}${{BASH_REMATCH[3]}}
+                expanded+=${{text%%"${{BASH_REMATCH[0]}}"*}}
+                if [ -n "${{!name+set}}" ]; then
+                    expanded+=${{!name}}
+                else
+                    expanded+=${{BASH_REMATCH[0]}}
+                fi
+                text=${{text#*"${{BASH_REMATCH[0]}}"}}
+            done
+            printf '%s' "$expanded$text"
+        }}
+
+        # Temporary files created by the script, see `--preserve-temp`.
+        bashtestmd_temp_files=()
+
+        # Buffers the output of the next command, see `--quiet-on-success`.
+        bashtestmd_quiet_start() {{
+            bashtestmd_quiet_log=$(mktemp)
+            bashtestmd_temp_files+=($bashtestmd_quiet_log)
+            exec {{bashtestmd_saved_stdout}}>&1 {{bashtestmd_saved_stderr}}>&2 &> "$bashtestmd_quiet_log"
+        }}
+
+        # Stops buffering the output, printing it if the argument is `show`.
+        bashtestmd_quiet_end() {{
+            if [[ -z "$bashtestmd_quiet_log" ]]; then
+                return
+            fi
+            exec 1>&$bashtestmd_saved_stdout 2>&$bashtestmd_saved_stderr {{bashtestmd_saved_stdout}}>&- {{bashtestmd_saved_stderr}}>&-
+            if [ "$1" = show ]; then
+                cat "$bashtestmd_quiet_log"
+            fi
+            bashtestmd_quiet_log=
+        }}
+        "#
+        ),
+        if options.tap {
+            concat!(
+                "    # The test point of the command, see `--tap`. Failures in subshells,\n",
+                "    # e.g. of `bashtestmd:retry`, are reported by the main shell, or by\n",
+                "    # the subshell of a block of a `bashtestmd:parallel-group`.\n",
+                "    if [ $BASH_SUBSHELL -eq $bashtestmd_tap_shell ]; then\n",
+                "        if [ -n \"$bashtestmd_test\" ]; then\n",
+                "            echo \"not ok $bashtestmd_test - $bashtestmd_test_name\"\n",
+                "        else\n",
+                "            echo \"Bail out!\"\n",
+                "        fi\n",
+                "    fi\n",
+            )
+        } else {
+            ""
+        },
+        if options.keep_going {
+            concat!(
+                "# A subshell (e.g. of `bashtestmd:retry`) is terminated, and the main\n",
+                "    # shell reports the failure. With `--keep-going`, the main shell only\n",
+                "    # skips the failed command, see `keep_going`.\n",
+                "    if [ $BASH_SUBSHELL -ne 0 ]; then\n",
+                "        exit $1\n",
+                "    fi\n",
+                "    return 1",
+            )
+        } else {
+            concat!(
+                "# A subshell (e.g. of `bashtestmd:retry` or `--atomic` mode) is\n",
+                "    # terminated, and the main shell reports the failure.\n",
+                "    if [ $BASH_SUBSHELL -ne 0 ]; then\n",
+                "        exit $1\n",
+                "    fi\n",
+                "    bashtestmd_final_exit",
+            )
+        }
+    ).unwrap();
+
+    let report_temp_files = if options.preserve_temp {
+        concat!(
+            "    if [ ${#bashtestmd_temp_files[@]} -ne 0 ]; then\n",
+            "        bashtestmd_msg echo \"Preserved temporary files:\"\n",
+            "        bashtestmd_msg printf '    %s\\n' \"${bashtestmd_temp_files[@]}\"\n",
+            "    fi\n",
+        )
+    } else {
+        ""
+    };
+    // The groups in the order they first appear in, with the number of
+    // commands in each.
+    let mut groups: Vec<(&str, usize)> = Vec::new();
+    for group in cmds.iter().filter_map(|cmd| cmd.group.as_deref()) {
+        match groups.iter_mut().find(|(name, _)| *name == group) {
+            Some((_, count)) => *count += 1,
+            None => groups.push((group, 1)),
+        }
+    }
+    let mut report_groups = String::new();
+    if !groups.is_empty() {
+        report_groups.push_str("    bashtestmd_msg echo \"Summary by group:\"\n");
+        for (idx, (name, count)) in groups.iter().enumerate() {
+            report_groups.push_str(&format!(
+                "    bashtestmd_msg echo {} \"$(grep -cx {idx} \"$bashtestmd_group_log\")/{count} passed\"\n",
+                shell_escape::escape(format!("    {name}:").into())
+            ));
+        }
+    }
+    let any_flaky = cmds.iter().any(|cmd| cmd.flaky);
+    let report_quarantined = if any_flaky {
+        concat!(
+            "    if [ -s \"$bashtestmd_quarantine_log\" ]; then\n",
+            "        bashtestmd_msg echo \"Quarantined failures of flaky commands:\"\n",
+            "        sed 's/^/    /' \"$bashtestmd_quarantine_log\"\n",
+            "    fi\n",
+        )
+    } else {
+        ""
+    };
+    let report_failures = if options.keep_going {
+        format!(
+            concat!(
+                "    if [ $bashtestmd_failures -ne 0 ]; then\n",
+                "        bashtestmd_msg echo \"$bashtestmd_failures of {} commands failed\"\n",
+                "    fi\n",
+            ),
+            cmds.len() + teardown.len()
+        )
+    } else {
+        String::new()
+    };
+    writeln!(
+        &mut script,
+        indoc!(
+            r#"
+            # The single place where the exit code of the script is decided.
+            bashtestmd_final_exit() {{
+            {}{}{}{}{}    if [ $bashtestmd_failures -ne 0 ]; then
+                    exit $bashtestmd_exit_code
+                fi
+            {}    exit 0
+            }}
+            "#
+        ),
+        // First, so that failures of the teardown count too.
+        if teardown.is_empty() {
+            ""
+        } else {
+            "    bashtestmd_teardown\n"
+        },
+        report_temp_files,
+        report_groups,
+        report_quarantined,
+        report_failures,
+        match &options.success_message {
+            Some(message) => format!(
+                "    bashtestmd_msg echo {}\n",
+                shell_escape::escape(message.into())
+            ),
+            None => String::new(),
+        }
+    )
+    .unwrap();
+    if !teardown.is_empty() {
+        writeln!(
+            &mut script,
+            indoc!(
+                r#"
+                # Runs the commands of the blocks tagged with `bashtestmd:teardown`,
+                # once and only in the main shell, e.g. not in the subshell of
+                # `--atomic` mode.
+                bashtestmd_teardown() {{
+                    if [[ -n "$bashtestmd_teardown_done" || $BASH_SUBSHELL -ne 0 ]]; then
+                        return
+                    fi
+                    bashtestmd_teardown_done=1"#
+            )
+        )
+        .unwrap();
+        for (idx, cmd) in teardown.iter().enumerate() {
+            let mut compiled = Vec::new();
+            // After the other commands.
+            let test = cmds.len() + idx + 1;
+            if options.tap {
+                cmd.start_test_point(test, &mut script).unwrap();
+            }
+            cmd.compile(options, &mut compiled).unwrap();
+            if options.tap {
+                cmd.end_test_point(test, &mut compiled).unwrap();
+            }
+            if options.keep_going {
+                compiled = keep_going(&compiled, cmd.cwd.is_some());
+            }
+            source_map.push(SourceMapEntry {
+                script_line: script.iter().filter(|&&byte| byte == b'\n').count()
+                    + 1
+                    + usize::from(options.keep_going),
+                markdown_line: cmd.line,
+                markdown_file: cmd.file.clone(),
+                cmd: cmd.cmd.clone(),
+            });
+            script.extend(compiled);
+        }
+        writeln!(&mut script, "}}\n").unwrap();
+    }
+    if any_flaky {
+        writeln!(
+            &mut script,
+            indoc!(
+                r#"
+                # The commands tagged with `bashtestmd:flaky` that failed, one per line.
+                bashtestmd_quarantine_log=$(mktemp)
+                bashtestmd_temp_files+=($bashtestmd_quarantine_log)
+                "#
+            )
+        )
+        .unwrap();
+    }
+    if !groups.is_empty() {
+        // A file rather than a variable, so that it also works in the
+        // subshell of `--atomic` mode.
+        writeln!(
+            &mut script,
+            indoc!(
+                r#"
+                # The indices of the groups of the commands that passed, one per line.
+                bashtestmd_group_log=$(mktemp)
+                bashtestmd_temp_files+=($bashtestmd_group_log)
+                "#
+            )
+        )
+        .unwrap();
+    }
+
+    if options.timings {
+        // `date` of BSD and macOS has no `%N`, and prints it as `N`.
+        writeln!(
+            &mut script,
+            indoc!(
+                r#"
+                # The current time in milliseconds, see `--timings`. Only in
+                # whole seconds where `date` doesn't support nanoseconds.
+                bashtestmd_now_ms() {{
+                    local now
+                    now=$(date +%s%N)
+                    if [[ $now =~ ^[0-9]+$ ]]; then
+                        echo $((now / 1000000))
+                    else
+                        echo $((${{now%%[!0-9]*}} * 1000))
+                    fi
+                }}
+                "#
+            )
+        )
+        .unwrap();
+    }
+
+    if let Some(max_runtime) = options.max_runtime {
+        // The script only handles the signal once its running command
+        // finished, so the watchdog terminates the processes of the script
+        // too, without being stopped halfway by the `EXIT` trap. Until then,
+        // its `sleep` doesn't keep the output open after the trap killed it.
+        writeln!(
+            &mut script,
+            indoc!(
+                r#"
+                # Terminates a process after its descendants.
+                bashtestmd_kill_tree() {{
+                    local child
+                    for child in $(pgrep -P $1); do
+                        bashtestmd_kill_tree $child
+                    done
+                    kill -TERM $1 2> /dev/null
+                }}
+
+                # Terminates the script after {0}s, see `--max-runtime`.
+                trap 'bashtestmd_fail $BASHTESTMD_EXIT_TIMEOUT; bashtestmd_final_exit' TERM
+                (
+                    sleep {0} > /dev/null 2>&1 < /dev/null
+                    trap '' TERM
+                    bashtestmd_msg echo "Overall timeout of {0}s exceeded, terminating"
+                    kill -TERM $$
+                    for bashtestmd_child in $(pgrep -P $$); do
+                        if [ $bashtestmd_child -ne $BASHPID ]; then
+                            bashtestmd_kill_tree $bashtestmd_child
+                        fi
+                    done
+                ) &
+                "#
+            ),
+            max_runtime
+        )
+        .unwrap();
+    }
+
+    if let Some(working_dir) = &options.working_dir {
+        // Before the container, which gets the working directory mounted.
+        writeln!(
+            &mut script,
+            indoc!(
+                r#"
+                if ! cd {0}; then
+                    bashtestmd_msg echo "Failed to change the working directory to "{0}
+                    bashtestmd_fail $BASHTESTMD_EXIT_COMMAND_FAILED{1}
+                fi
+                "#
+            ),
+            shell_escape::escape(working_dir.into()),
+            abort
+        )
+        .unwrap();
+    }
+
+    if let Some(image) = &options.docker {
+        // A single container for all commands, so that their changes to the
+        // file system carry over. The working directory is mounted at the
+        // same path, so that absolute paths work too.
+        writeln!(
+            &mut script,
+            indoc!(
+                r#"
+                bashtestmd_msg echo "Starting a container from the image "{0}
+                if ! bashtestmd_container=$(docker run -d --rm -v "$PWD:$PWD" -w "$PWD" --entrypoint sleep {0} infinity); then
+                    bashtestmd_msg echo "Failed to start the container"
+                    bashtestmd_fail $BASHTESTMD_EXIT_COMMAND_FAILED{1}
+                fi
+                "#
+            ),
+            shell_escape::escape(image.into()),
+            abort
+        )
+        .unwrap();
+    }
+
+    if options.atomic {
+        writeln!(&mut script, "(").unwrap();
+        writeln!(&mut script, "bashtestmd_atomic_teardown() {{").unwrap();
+        writeln!(&mut script, ":").unwrap();
+        for cmd in &options.after {
+            cmd.compile(options, &mut script).unwrap();
+        }
+        writeln!(&mut script, "}}").unwrap();
+        // A subshell doesn't inherit the `EXIT` trap of the script.
+        writeln!(
+            &mut script,
+            r#"trap '{show_quiet_output}jobs -p | xargs -r kill 2> /dev/null; bashtestmd_atomic_teardown' EXIT"#
+        )
+        .unwrap();
+        for cmd in &options.before {
+            cmd.compile(options, &mut script).unwrap();
+        }
+    }
+    if options.tap {
+        // The plan of the TAP output.
+        writeln!(&mut script, "echo 1..{}", cmds.len() + teardown.len()).unwrap();
+    }
+    let compile_command = |cmd: &Command| {
+        let mut compiled = Vec::new();
+        cmd.compile(options, &mut compiled).unwrap();
+        if let Some(group) = &cmd.group {
+            // Only reached if the command passed, or if it's flaky.
+            let idx = groups.iter().position(|(name, _)| name == group).unwrap();
+            if cmd.flaky || cmd.allow_fail {
+                writeln!(
+                    &mut compiled,
+                    r#"[ -z "$bashtestmd_flaky_failed" ] && echo {idx} >> "$bashtestmd_group_log""#
+                )
+                .unwrap();
+            } else {
+                writeln!(&mut compiled, r#"echo {idx} >> "$bashtestmd_group_log""#).unwrap();
+            }
+        }
+        compiled
+    };
+    let mut previous_group = None;
+    let mut idx = 0;
+    while idx < cmds.len() {
+        let cmd = &cmds[idx];
+        // The consecutive commands of a parallel group run at the same time.
+        let batch = match &cmd.parallel_group {
+            Some(parallel_group) => cmds[idx..]
+                .iter()
+                .take_while(|next| next.parallel_group.as_ref() == Some(parallel_group))
+                .count(),
+            None => 1,
+        };
+        if let Some(group) = cmd.group.as_ref().filter(|_| cmd.group != previous_group) {
+            writeln!(
+                &mut script,
+                "bashtestmd_msg echo {}",
+                shell_escape::escape(format!("=== {group} ===").into())
+            )
+            .unwrap();
+        }
+        previous_group = cmds[idx + batch - 1].group.clone();
+        if options.tap && cmd.parallel_group.is_none() {
+            cmd.start_test_point(idx + 1, &mut script).unwrap();
+        }
+        if let Some(max_duration) = options.max_total_duration {
+            // `$SECONDS` counts from the start of the script.
+            writeln!(
+                &mut script,
+                indoc!(
+                    r#"
+                    if [ $SECONDS -ge {0} ]; then
+                        bashtestmd_msg echo "The script ran for ${{SECONDS}}s, exceeding its total budget of {0}s. Aborting"
+                        check_and_output_long_running_output
+                        bashtestmd_fail $BASHTESTMD_EXIT_TIMEOUT{1}
+                    fi"#
+                ),
+                max_duration,
+                abort
+            )
+            .unwrap();
+        }
+        if let Some(parallel_group) = &cmd.parallel_group {
+            compile_parallel_group(
+                parallel_group,
+                &cmds[idx..idx + batch],
+                idx,
+                options,
+                compile_command,
+                &mut script,
+                &mut source_map,
+            );
+            idx += batch;
+            continue;
+        }
+        let mut compiled = compile_command(cmd);
+        if options.tap {
+            cmd.end_test_point(idx + 1, &mut compiled).unwrap();
+        }
+        if options.keep_going {
+            compiled = keep_going(&compiled, cmd.cwd.is_some());
+        }
+        source_map.push(SourceMapEntry {
+            script_line: script.iter().filter(|&&byte| byte == b'\n').count()
+                + 1
+                + usize::from(options.keep_going),
+            markdown_line: cmd.line,
+            markdown_file: cmd.file.clone(),
+            cmd: cmd.cmd.clone(),
+        });
+        script.extend(compiled);
+        idx += 1;
+    }
+    if options.atomic {
+        // Failures terminate the subshell with the exit code of the failure,
+        // which is passed on here.
+        writeln!(&mut script, ") || bashtestmd_fail $?").unwrap();
+    }
+    writeln!(&mut script, "bashtestmd_final_exit").unwrap();
+    (String::from_utf8(script).unwrap(), source_map)
+}
+
+/// Runs the commands of a `bashtestmd:parallel-group` at the same time, with
+/// the commands of each block in a subshell in the background, and waits for
+/// all of them. The output of each block is buffered, and printed in the order
+/// of the blocks once it finished. `first` is the index of the first command
+/// among all commands, for the TAP test points.
+fn compile_parallel_group(
+    parallel_group: &str,
+    cmds: &[Command],
+    first: usize,
+    options: &ScriptOptions,
+    compile_command: impl Fn(&Command) -> Vec<u8>,
+    script: &mut Vec<u8>,
+    source_map: &mut Vec<SourceMapEntry>,
+) {
+    // The commands of a block stay in order, one after the other.
+    let mut blocks: Vec<&[Command]> = Vec::new();
+    let mut start = 0;
+    for end in 1..=cmds.len() {
+        if end == cmds.len() || cmds[end].block.is_none() || cmds[end].block != cmds[start].block {
+            blocks.push(&cmds[start..end]);
+            start = end;
+        }
+    }
+    writeln!(
+        script,
+        "# The {} blocks of the parallel group {}",
+        blocks.len(),
+        shell_escape::escape(parallel_group.into())
+    )
+    .unwrap();
+    let mut test = first + 1;
+    for (job, block) in blocks.iter().enumerate() {
+        writeln!(script, "bashtestmd_parallel_logs[{job}]=$(mktemp)").unwrap();
+        writeln!(
+            script,
+            "bashtestmd_temp_files+=(${{bashtestmd_parallel_logs[{job}]}})"
+        )
+        .unwrap();
+        writeln!(script, "(").unwrap();
+        if options.tap {
+            // The block reports the failures of its commands itself.
+            writeln!(script, "bashtestmd_tap_shell=$BASH_SUBSHELL").unwrap();
+        }
+        for cmd in block.iter() {
+            if options.tap {
+                cmd.start_test_point(test, &mut *script).unwrap();
+            }
+            let mut compiled = compile_command(cmd);
+            if options.tap {
+                cmd.end_test_point(test, &mut compiled).unwrap();
+            }
+            source_map.push(SourceMapEntry {
+                script_line: script.iter().filter(|&&byte| byte == b'\n').count() + 1,
+                markdown_line: cmd.line,
+                markdown_file: cmd.file.clone(),
+                cmd: cmd.cmd.clone(),
+            });
+            script.extend(compiled);
+            test += 1;
+        }
+        writeln!(
+            script,
+            r#") > "${{bashtestmd_parallel_logs[{job}]}}" 2>&1 &"#
+        )
+        .unwrap();
+        writeln!(script, "bashtestmd_parallel_pids[{job}]=$!").unwrap();
+    }
+    if options.tap {
+        writeln!(script, "bashtestmd_tap_shell=-1").unwrap();
+    }
+    for job in 0..blocks.len() {
+        let mut joined = format!(
+            indoc!(
+                r#"
+                wait ${{bashtestmd_parallel_pids[{0}]}}
+                bashtestmd_status=$?
+                cat "${{bashtestmd_parallel_logs[{0}]}}"
+                if [ $bashtestmd_status -ne 0 ]; then
+                    bashtestmd_fail $bashtestmd_status{on_fail}
+                fi
+                "#
+            ),
+            job,
+            on_fail = options.on_fail()
+        )
+        .into_bytes();
+        if options.keep_going {
+            // The blocks changed directories in their own subshells.
+            joined = keep_going(&joined, false);
+        }
+        script.extend(joined);
+    }
+    if options.tap {
+        writeln!(script, "bashtestmd_tap_shell=0").unwrap();
+    }
+}
+
+/// Wraps the compiled code of a command in a loop that its failures break out
+/// of with `--keep-going` (see `ScriptOptions::on_fail`), so that the script
+/// carries on with the next command. If the command has a `bashtestmd:cd` tag, the script then goes
+/// back to the previous directory, since the failure skipped the `popd`.
+fn keep_going(compiled: &[u8], cwd: bool) -> Vec<u8> {
+    let mut wrapped = b"while true; do\n".to_vec();
+    wrapped.extend(compiled);
+    wrapped.extend(b"break\ndone\n");
+    if cwd {
+        wrapped
+            .extend(b"while [ ${#DIRSTACK[@]} -gt $bashtestmd_dirs ]; do popd > /dev/null; done\n");
+    }
+    wrapped
+}
+
+/// Describes the commands as a JSON array with an object per command, e.g.
+/// `[{"cmd":"echo hello","line":2,"block":0,"long_running":false,"exit_code":0,"expected_output":"hello\n","wait_until":null}]`.
+/// The exit code is `null` if it's ignored, and a string like `"1-125"` or
+/// `"non-zero"` if it's not a single one.
+pub fn commands_json(cmds: &[Command]) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+    let commands: Vec<String> = cmds
+        .iter()
+        .map(|cmd| {
+            format!(
+                r#"{{"cmd":{},"line":{},"block":{},"long_running":{},"exit_code":{},"expected_output":{},"wait_until":{}}}"#,
+                json_string(&cmd.cmd),
+                optional(cmd.line.map(|line| line.to_string())),
+                optional(cmd.block.map(|block| block.to_string())),
+                cmd.long_running,
+                optional(cmd.exit_code.map(|exit_code| match exit_code {
+                    ExitCode::Exactly(code) => code.to_string(),
+                    _ => json_string(&exit_code.to_string()),
+                })),
+                optional(cmd.expected_output.as_deref().map(json_string)),
+                optional(cmd.wait_until.as_deref().map(json_string))
+            )
+        })
+        .collect();
+    format!("[{}]", commands.join(","))
+}
+
+/// Describes the commands for people, e.g. when reviewing changes to a
+/// document, with a numbered entry per command and its checks:
+///
+/// ```text
+/// 1. echo hello (README.md:2)
+///    exit code: 0
+///    expected output:
+///      hello
+/// ```
+pub fn commands_listing(cmds: &[Command]) -> String {
+    let mut listing = String::new();
+    for (idx, cmd) in cmds.iter().enumerate() {
+        listing.push_str(&format!("{}. {}", idx + 1, cmd.cmd.replace('\n', "\n   ")));
+        match (&cmd.file, cmd.line) {
+            (Some(file), Some(line)) => listing.push_str(&format!(" ({file}:{line})")),
+            (None, Some(line)) => listing.push_str(&format!(" (line {line})")),
+            _ => {}
+        }
+        listing.push('\n');
+        let mut details = Vec::new();
+        if cmd.teardown {
+            details.push("runs when the script exits".to_string());
+        }
+        if cmd.long_running {
+            details.push(match &cmd.wait_until {
+                Some(wait_until) => {
+                    format!("long running, waits until the output contains '{wait_until}'")
+                }
+                None if cmd.daemon => "long running, must still run after it started".to_string(),
+                None => "long running".to_string(),
+            });
+        } else {
+            details.push(match cmd.exit_code {
+                Some(exit_code) => format!("exit code: {exit_code}"),
+                None => "exit code: ignored".to_string(),
+            });
+        }
+        let texts = [
+            ("expected output", &cmd.expected_output),
+            ("expected stderr", &cmd.expected_stderr),
+            ("must not output", &cmd.unexpected_output),
+        ];
+        for (label, text) in texts {
+            let Some(text) = text else {
+                continue;
+            };
+            let exact = if cmd.exact_output && label == "expected output" {
+                " (exact)"
+            } else {
+                ""
+            };
+            details.push(format!("{label}{exact}:"));
+            for line in text.lines() {
+                details.push(format!("  {line}"));
+            }
+        }
+        for detail in details {
+            listing.push_str(&format!("   {detail}\n"));
+        }
+    }
+    listing
+}
+
+/// Quotes `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+struct CodeBlockTags {
+    long_running: bool,
+    compare_output: bool,
+    compare_output_exact: bool,
+    normalization: Normalization,
+    output_not_contains: bool,
+    compare_stderr: bool,
+    exit_code: Option<ExitCode>,
+    wait_until: Option<String>,
+    wait_until_regex: bool,
+    wait_until_interval: Option<u64>,
+    wait_until_timeout: Option<u64>,
+    background_delay: Option<u64>,
+    daemon: bool,
+    expect_diff: Option<String>,
+    trace_to: Option<String>,
+    expect_sha256: Option<String>,
+    wait_on: WaitOn,
+    expect_no_output_change: bool,
+    expect_output_unchanged_across: Option<usize>,
+    show_output: bool,
+    expect_output_empty_lines: Option<usize>,
+    pipefail: bool,
+    env: Vec<(String, String)>,
+    expect_output_matches_command: Option<String>,
+    max_capture_bytes: Option<usize>,
+    env_file: Option<String>,
+    expect_no_command: bool,
+    expect_output_json_eq: bool,
+    cache_key: Option<String>,
+    retry_on_output: Option<String>,
+    retry_attempts: Option<usize>,
+    retry: Option<usize>,
+    retry_delay: Option<u64>,
+    timeout: Option<u64>,
+    define_output: Option<String>,
+    use_output: Option<String>,
+    stdin_file: Option<String>,
+    stdin: bool,
+    script: bool,
+    expect_schema: Option<String>,
+    expected_output_file: Option<String>,
+    expect_no_file: Vec<String>,
+    expect_files_equal: Vec<(String, String)>,
+    asserts: Vec<String>,
+    match_patterns: Vec<String>,
+    output_matches: Vec<String>,
+    group: Option<String>,
+    parallel_group: Option<String>,
+    capture: Option<String>,
+    expand_expected: bool,
+    strip_ansi: bool,
+    strip_comments: bool,
+    flaky: bool,
+    allow_fail: bool,
+    ulimits: Vec<(char, String)>,
+    expect_signal: Option<&'static str>,
+    isolated: bool,
+    chdir: Option<String>,
+    cwd: Option<String>,
+    output_follows: bool,
+    only: bool,
+    setup: bool,
+    teardown: bool,
+    skip: bool,
+}
+
+/// Removes the whitespace at the end of each line of its input.
+const TRIM_LINES: &str = "sed 's/[[:space:]]*$//'";
+
+/// Pairs of tags that contradict each other, without their `bashtestmd:`
+/// prefix, with why if it's not obvious.
+const CONFLICTING_TAGS: [(&str, &str, &str); 38] = [
+    ("setup", "teardown", ""),
+    ("flaky", "allow-fail", ""),
+    ("exit-code", "exit-code-ignore", ""),
+    ("exit-code", "exit-code-nonzero", ""),
+    ("exit-code-ignore", "exit-code-nonzero", ""),
+    (
+        "expect-signal",
+        "exit-code",
+        "the signal sets the exit code",
+    ),
+    (
+        "expect-signal",
+        "exit-code-ignore",
+        "the signal sets the exit code",
+    ),
+    (
+        "expect-signal",
+        "exit-code-nonzero",
+        "the signal sets the exit code",
+    ),
+    ("compare-output", "compare-output-exact", ""),
+    (
+        "compare-output-exact",
+        "compare-output-ci",
+        "the comparison is exact",
+    ),
+    (
+        "compare-output-exact",
+        "compare-output-trim",
+        "the comparison is exact",
+    ),
+    ("compare-stderr", "compare-output", STREAMS),
+    ("compare-stderr", "compare-output-exact", STREAMS),
+    (
+        "compare-output",
+        "output-not-contains",
+        "the text of the block can't be both expected and unexpected",
+    ),
+    ("stdin", "stdin-file", ""),
+    ("stdin", "script", "the text of the block is the script"),
+    ("stdin", "compare-output", STDIN),
+    ("stdin", "compare-output-exact", STDIN),
+    ("stdin", "compare-stderr", STDIN),
+    ("stdin", "output-not-contains", STDIN),
+    ("stdin", "expect-output-json-eq", STDIN),
+    ("long-running", "compare-output", LONG_RUNNING),
+    ("long-running", "compare-output-exact", LONG_RUNNING),
+    ("long-running", "compare-stderr", LONG_RUNNING),
+    ("long-running", "output-not-contains", LONG_RUNNING),
+    ("long-running", "output-follows", LONG_RUNNING),
+    ("long-running", "expected-output-file", LONG_RUNNING),
+    ("daemon", "compare-output", LONG_RUNNING),
+    ("daemon", "compare-output-exact", LONG_RUNNING),
+    ("daemon", "compare-stderr", LONG_RUNNING),
+    ("daemon", "output-not-contains", LONG_RUNNING),
+    ("daemon", "output-follows", LONG_RUNNING),
+    (
+        "daemon",
+        "wait-until",
+        "use `bashtestmd:long-running` to wait for the text",
+    ),
+    ("parallel-group", "long-running", PARALLEL),
+    ("parallel-group", "daemon", PARALLEL),
+    ("parallel-group", "capture", PARALLEL),
+    ("parallel-group", "chdir", PARALLEL),
+    ("parallel-group", "teardown", PARALLEL),
+];
+
+/// Why the expected output can't be compared with both streams.
+const STREAMS: &str = "the expected output is compared with only one of the streams";
+/// Why a `bashtestmd:stdin` block has no expected output.
+const STDIN: &str = "the text of the block is the input of the command, so it can't be the expected output of a comparison too";
+/// Why a long running command has no output checks.
+const LONG_RUNNING: &str = "the output of a long running command isn't checked";
+/// Why a block of a parallel group can't change the state of the script.
+const PARALLEL: &str = "the blocks of a parallel group run in subshells, so their changes to the state of the script don't carry over";
+
+/// The tags that can be given more than once with different values, without
+/// their `bashtestmd:` prefix.
+const REPEATABLE_TAGS: [&str; 8] = [
+    "env",
+    "assert",
+    "match-pattern",
+    "output-matches",
+    "expect-no-file",
+    "expect-files-equal",
+    "ulimit",
+    "matrix",
+];
+
+/// Seconds after which a command tagged with `bashtestmd:daemon` must still
+/// run, unless it has a `bashtestmd:background-delay`.
+const DAEMON_GRACE_PERIOD: u64 = 1;
+
+/// The resource limits supported by `bashtestmd:ulimit`, by their option of
+/// `ulimit`: core file size, file size, open files, stack size, CPU time,
+/// processes and virtual memory.
+const ULIMITS: [char; 7] = ['c', 'f', 'n', 's', 't', 'u', 'v'];
+
+/// The signals supported by `bashtestmd:expect-signal` and their numbers,
+/// which are the same on Linux and macOS.
+const SIGNALS: [(&str, i32); 11] = [
+    ("HUP", 1),
+    ("INT", 2),
+    ("QUIT", 3),
+    ("ILL", 4),
+    ("TRAP", 5),
+    ("ABRT", 6),
+    ("KILL", 9),
+    ("SEGV", 11),
+    ("PIPE", 13),
+    ("ALRM", 14),
+    ("TERM", 15),
+];
+
+/// The patterns of `bashtestmd:match-pattern` by their names, as extended
+/// regular expressions for `grep -E`.
+const PATTERNS: [(&str, &str); 5] = [
+    (
+        "iso8601",
+        "[0-9]{4}-[0-9]{2}-[0-9]{2}T[0-9]{2}:[0-9]{2}:[0-9]{2}([.,][0-9]+)?(Z|[+-][0-9]{2}(:?[0-9]{2})?)?",
+    ),
+    (
+        "uuid",
+        "(^|[^0-9A-Fa-f-])[0-9A-Fa-f]{8}-[0-9A-Fa-f]{4}-[0-9A-Fa-f]{4}-[0-9A-Fa-f]{4}-[0-9A-Fa-f]{12}([^0-9A-Fa-f-]|$)",
+    ),
+    (
+        "sha256",
+        "(^|[^0-9A-Fa-f])[0-9A-Fa-f]{64}([^0-9A-Fa-f]|$)",
+    ),
+    (
+        "semver",
+        r"(^|[^0-9.])(0|[1-9][0-9]*)\.(0|[1-9][0-9]*)\.(0|[1-9][0-9]*)(-[0-9A-Za-z.-]+)?(\+[0-9A-Za-z.-]+)?([^0-9.]|$)",
+    ),
+    (
+        "ipv4",
+        r"(^|[^0-9.])((25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9]?[0-9])\.){3}(25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9]?[0-9])([^0-9.]|$)",
+    ),
+];
+
+/// The comma-separated tokens of the info string of a code block's fence,
+/// e.g. `["sh", "test-ci", "bashtestmd:compare-output"]`. Commas in double
+/// quotes don't separate tokens, so that tag values can contain them, e.g.
+/// `bashtestmd:wait-until="Server started, listening"`. The quotes are
+/// removed.
+pub fn fence_tokens(code_block: &mdast::Code) -> Vec<String> {
+    // The parser splits the info string of the fence at the first space,
+    // but tag values may contain spaces.
+    let info = match &code_block.meta {
+        Some(meta) => format!("{} {meta}", code_block.lang.as_deref().unwrap_or_default()),
+        None => code_block.lang.clone().unwrap_or_default(),
+    };
+
+    let mut tokens = vec![String::new()];
+    let mut in_quotes = false;
+    for c in info.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => tokens.push(String::new()),
+            c => tokens.last_mut().unwrap().push(c),
+        }
+    }
+    tokens
+}
+
+/// Describes the contradictions among the tags of `code_block`: the pairs of
+/// `CONFLICTING_TAGS`, and tags that aren't `REPEATABLE_TAGS` with different
+/// values.
+fn tag_conflicts(code_block: &mdast::Code) -> Vec<String> {
+    let mut values: Vec<(String, Vec<String>)> = Vec::new();
+    for token in fence_tokens(code_block) {
+        let Some(tag) = token.strip_prefix("bashtestmd:") else {
+            continue;
+        };
+        let (name, value) = tag.split_once('=').unwrap_or((tag, ""));
+        match values.iter_mut().find(|(other, _)| other == name) {
+            Some((_, tag_values)) if !tag_values.iter().any(|other| other == value) => {
+                tag_values.push(value.to_string())
+            }
+            Some(_) => {}
+            None => values.push((name.to_string(), vec![value.to_string()])),
+        }
+    }
+
+    let mut conflicts = Vec::new();
+    for (a, b, reason) in CONFLICTING_TAGS {
+        if values.iter().any(|(name, _)| name == a) && values.iter().any(|(name, _)| name == b) {
+            let reason = if reason.is_empty() {
+                String::new()
+            } else {
+                format!(", {reason}")
+            };
+            conflicts.push(format!(
+                "a block can't be tagged with both `bashtestmd:{a}` and `bashtestmd:{b}`{reason}"
+            ));
+        }
+    }
+    for (name, tag_values) in values {
+        if tag_values.len() > 1 && !REPEATABLE_TAGS.contains(&name.as_str()) {
+            conflicts.push(format!(
+                "`bashtestmd:{name}` is given different values: {}",
+                tag_values.join(", ")
+            ));
+        }
+    }
+    conflicts
+}
+
+/// Whether one of the tags of `code_block` is exactly `tag`, so that e.g.
+/// `test` doesn't select blocks tagged with `test-ci`.
+fn is_tagged(code_block: &mdast::Code, tag: &str) -> bool {
+    fence_tokens(code_block)
+        .iter()
+        .any(|token| token.trim() == tag)
+}
+
+/// The languages of the code blocks that are converted without a tag.
+const SHELL_LANGUAGES: [&str; 3] = ["bash", "sh", "shell"];
+
+/// Whether `code_block` is converted for `tag`, i.e. it's tagged with it, or
+/// it's in a shell language if `tag` is empty.
+pub fn is_selected(code_block: &mdast::Code, tag: &str) -> bool {
+    if tag.is_empty() {
+        return SHELL_LANGUAGES.contains(&fence_tokens(code_block)[0].trim());
+    }
+    is_tagged(code_block, tag)
+}
+
+impl CodeBlockTags {
+    /// Parses the tags of `code_block`, failing on tags with invalid values.
+    fn parse(
+        code_block: &mdast::Code,
+        location: &str,
+        diagnostics: &mut Diagnostics,
+    ) -> Result<Self, String> {
+        let mut langs = fence_tokens(code_block);
+
+        let mut tags = Self {
+            long_running: false,
+            compare_output: false,
+            compare_output_exact: false,
+            normalization: Normalization::default(),
+            output_not_contains: false,
+            compare_stderr: false,
+            exit_code: Some(ExitCode::Exactly(0)),
+            wait_until: None,
+            wait_until_regex: false,
+            wait_until_interval: None,
+            wait_until_timeout: None,
+            background_delay: None,
+            daemon: false,
+            expect_diff: None,
+            trace_to: None,
+            expect_sha256: None,
+            wait_on: WaitOn::Both,
+            expect_no_output_change: false,
+            expect_output_unchanged_across: None,
+            show_output: false,
+            expect_output_empty_lines: None,
+            pipefail: false,
+            env: Vec::new(),
+            expect_output_matches_command: None,
+            max_capture_bytes: None,
+            env_file: None,
+            expect_no_command: false,
+            expect_output_json_eq: false,
+            cache_key: None,
+            retry_on_output: None,
+            retry_attempts: None,
+            retry: None,
+            retry_delay: None,
+            timeout: None,
+            define_output: None,
+            use_output: None,
+            stdin_file: None,
+            stdin: false,
+            script: false,
+            expect_schema: None,
+            expected_output_file: None,
+            expect_no_file: Vec::new(),
+            expect_files_equal: Vec::new(),
+            asserts: Vec::new(),
+            match_patterns: Vec::new(),
+            output_matches: Vec::new(),
+            group: None,
+            parallel_group: None,
+            capture: None,
+            expand_expected: false,
+            strip_ansi: false,
+            strip_comments: false,
+            flaky: false,
+            allow_fail: false,
+            ulimits: Vec::new(),
+            expect_signal: None,
+            isolated: false,
+            chdir: None,
+            cwd: None,
+            output_follows: false,
+            only: false,
+            setup: false,
+            teardown: false,
+            skip: false,
+        };
+
+        // A skipped block is dropped as a whole, whatever its other tags are.
+        if langs.iter().any(|lang| lang == "bashtestmd:skip") {
+            tags.skip = true;
+            return Ok(tags);
+        }
+        // A tag given twice counts once, see `tag_conflicts` for different
+        // values.
+        let mut seen = HashSet::new();
+        langs.retain(|lang| seen.insert(lang.clone()));
+
+        for lang in langs {
+            if lang == "bashtestmd:long-running" {
+                tags.long_running = true;
+            } else if lang == "bashtestmd:daemon" {
+                tags.long_running = true;
+                tags.daemon = true;
+            } else if lang == "bashtestmd:only" {
+                tags.only = true;
+            } else if lang == "bashtestmd:setup" {
+                tags.setup = true;
+            } else if lang == "bashtestmd:teardown" {
+                tags.teardown = true;
+            } else if lang == "bashtestmd:expect-no-output-change" {
+                tags.expect_no_output_change = true;
+            } else if lang == "bashtestmd:show-output" {
+                tags.show_output = true;
+            } else if lang == "bashtestmd:pipefail" {
+                tags.pipefail = true;
+            } else if lang == "bashtestmd:expect-no-command" {
+                tags.expect_no_command = true;
+            } else if lang == "bashtestmd:expect-output-json-eq" {
+                tags.expect_output_json_eq = true;
+            } else if lang == "bashtestmd:stdin" {
+                tags.stdin = true;
+            } else if lang == "bashtestmd:script" {
+                tags.script = true;
+            } else if lang == "bashtestmd:isolated" {
+                tags.isolated = true;
+            } else if lang == "bashtestmd:flaky" {
+                tags.flaky = true;
+            } else if lang == "bashtestmd:allow-fail" {
+                tags.allow_fail = true;
+            } else if lang == "bashtestmd:expand-expected" {
+                tags.expand_expected = true;
+            } else if lang == "bashtestmd:strip-ansi" {
+                tags.strip_ansi = true;
+            } else if lang == "bashtestmd:strip-comments" {
+                tags.strip_comments = true;
+            } else if lang == "bashtestmd:output-follows" {
+                tags.output_follows = true;
+            } else if lang == "bashtestmd:compare-output" {
+                tags.compare_output = true;
+            } else if lang == "bashtestmd:compare-output-exact" {
+                tags.compare_output_exact = true;
+            } else if lang == "bashtestmd:compare-output-ci" {
+                tags.normalization.ignore_case = true;
+            } else if lang == "bashtestmd:compare-output-trim" {
+                tags.normalization.collapse_whitespace = true;
+            } else if lang == "bashtestmd:output-not-contains" {
+                tags.output_not_contains = true;
+            } else if lang == "bashtestmd:compare-stderr" {
+                tags.compare_stderr = true;
+            } else if lang == "bashtestmd:exit-code-ignore" {
+                tags.exit_code = None;
+            } else if lang == "bashtestmd:exit-code-nonzero" {
+                tags.exit_code = Some(ExitCode::NonZero);
+            } else if lang.starts_with("bashtestmd:exit-code=") {
+                let exit_code = tag_value(&lang)?;
+                tags.exit_code = Some(exit_code);
+            } else if lang.starts_with("bashtestmd:wait-until=") {
+                let wait_until = lang.split_once('=').unwrap().1.to_string();
+                // Every output would match.
+                if wait_until.is_empty() {
+                    return Err(format!("empty text in bashtestmd tag: {lang}"));
+                }
+                tags.wait_until = Some(wait_until);
+            } else if lang == "bashtestmd:wait-until-regex" {
+                tags.wait_until_regex = true;
+            } else if lang.starts_with("bashtestmd:wait-until-interval=") {
+                let interval = tag_value(&lang)?;
+                tags.wait_until_interval = Some(interval);
+            } else if lang.starts_with("bashtestmd:wait-until-timeout=") {
+                let timeout = tag_value(&lang)?;
+                tags.wait_until_timeout = Some(timeout);
+            } else if lang.starts_with("bashtestmd:background-delay=") {
+                let delay = tag_value(&lang)?;
+                tags.background_delay = Some(delay);
+            } else if lang.starts_with("bashtestmd:expect-diff=") {
+                let expect_diff = lang.split_once('=').unwrap().1.to_string();
+                tags.expect_diff = Some(expect_diff);
+            } else if lang.starts_with("bashtestmd:trace-to=") {
+                let trace_to = lang.split_once('=').unwrap().1.to_string();
+                tags.trace_to = Some(trace_to);
+            } else if lang.starts_with("bashtestmd:expect-sha256=") {
+                let expect_sha256 = lang.split_once('=').unwrap().1.to_string();
+                tags.expect_sha256 = Some(expect_sha256);
+            } else if lang.starts_with("bashtestmd:expect-output-empty-lines=") {
+                let empty_lines = tag_value(&lang)?;
+                tags.expect_output_empty_lines = Some(empty_lines);
+            } else if lang.starts_with("bashtestmd:expect-output-unchanged-across=") {
+                let runs = tag_value(&lang)?;
+                tags.expect_output_unchanged_across = Some(runs);
+            } else if lang.starts_with("bashtestmd:locale=") {
+                let locale = lang.split_once('=').unwrap().1.to_string();
+                tags.env.push(("LC_ALL".to_string(), locale));
+            } else if lang.starts_with("bashtestmd:cache-key=") {
+                let cache_key = lang.split_once('=').unwrap().1.to_string();
+                tags.cache_key = Some(cache_key);
+            } else if lang.starts_with("bashtestmd:env=") {
+                let variable = lang.split_once('=').unwrap().1;
+                let Some((key, value)) = variable.split_once('=') else {
+                    return Err(format!(
+                        "invalid tag {lang}, expected bashtestmd:env=KEY=VALUE"
+                    ));
+                };
+                if !is_variable_name(key) {
+                    return Err(format!("invalid variable name in tag {lang}"));
+                }
+                tags.env.push((key.to_string(), value.to_string()));
+            } else if lang.starts_with("bashtestmd:env-file=") {
+                let env_file = lang.split_once('=').unwrap().1.to_string();
+                tags.env_file = Some(env_file);
+            } else if lang.starts_with("bashtestmd:tz=") {
+                let tz = lang.split_once('=').unwrap().1.to_string();
+                tags.env.push(("TZ".to_string(), tz));
+            } else if lang.starts_with("bashtestmd:expect-output-matches-command=") {
+                let other_cmd = lang.split_once('=').unwrap().1.to_string();
+                tags.expect_output_matches_command = Some(other_cmd);
+            } else if lang.starts_with("bashtestmd:max-capture-bytes=") {
+                let max_bytes = tag_value(&lang)?;
+                tags.max_capture_bytes = Some(max_bytes);
+            } else if lang.starts_with("bashtestmd:retry-on-output=") {
+                let pattern = lang.split_once('=').unwrap().1.to_string();
+                tags.retry_on_output = Some(pattern);
+            } else if lang.starts_with("bashtestmd:retry-attempts=") {
+                let attempts = tag_value(&lang)?;
+                tags.retry_attempts = Some(attempts);
+            } else if lang.starts_with("bashtestmd:retry=") {
+                let attempts = tag_value(&lang)?;
+                if attempts == 0 {
+                    return Err(format!(
+                        "invalid value in bashtestmd tag {lang} (at least one attempt is needed)"
+                    ));
+                }
+                tags.retry = Some(attempts);
+            } else if lang.starts_with("bashtestmd:retry-delay=") {
+                let delay = tag_value(&lang)?;
+                tags.retry_delay = Some(delay);
+            } else if lang.starts_with("bashtestmd:timeout=") {
+                let timeout = tag_value(&lang)?;
+                tags.timeout = Some(timeout);
+            } else if lang.starts_with("bashtestmd:define-output=") {
+                let name = lang.split_once('=').unwrap().1.to_string();
+                tags.define_output = Some(name);
+            } else if lang.starts_with("bashtestmd:use-output=") {
+                let name = lang.split_once('=').unwrap().1.to_string();
+                tags.use_output = Some(name);
+            } else if lang.starts_with("bashtestmd:chdir=") {
+                let chdir = lang.split_once('=').unwrap().1.to_string();
+                tags.chdir = Some(chdir);
+            } else if lang.starts_with("bashtestmd:cd=") {
+                let cwd = lang.split_once('=').unwrap().1.to_string();
+                tags.cwd = Some(cwd);
+            } else if lang.starts_with("bashtestmd:stdin-file=") {
+                let stdin_file = lang.split_once('=').unwrap().1.to_string();
+                tags.stdin_file = Some(stdin_file);
+            } else if lang.starts_with("bashtestmd:expect-schema=") {
+                let schema = lang.split_once('=').unwrap().1.to_string();
+                tags.expect_schema = Some(schema);
+            } else if lang.starts_with("bashtestmd:expected-output-file=") {
+                let path = lang.split_once('=').unwrap().1.to_string();
+                tags.expected_output_file = Some(path);
+            } else if lang.starts_with("bashtestmd:expect-no-file=") {
+                let path = lang.split_once('=').unwrap().1.to_string();
+                tags.expect_no_file.push(path);
+            } else if lang.starts_with("bashtestmd:expect-files-equal=") {
+                let (a, b) = lang
+                    .split_once('=')
+                    .unwrap()
+                    .1
+                    .split_once(':')
+                    .ok_or_else(|| format!("expected PATH_A:PATH_B in bashtestmd tag: {lang}"))?;
+                tags.expect_files_equal.push((a.to_string(), b.to_string()));
+            } else if lang.starts_with("bashtestmd:assert=") {
+                let expr = lang.split_once('=').unwrap().1.trim();
+                if expr.is_empty() {
+                    warn!(
+                        diagnostics,
+                        "{location}Empty expression in bashtestmd tag, ignoring: {lang}"
+                    );
+                } else {
+                    tags.asserts.push(expr.to_string());
+                }
+            } else if lang.starts_with("bashtestmd:match-pattern=") {
+                let name = lang.split_once('=').unwrap().1.to_string();
+                tags.match_patterns.push(name);
+            } else if lang.starts_with("bashtestmd:output-matches=") {
+                let pattern = lang.split_once('=').unwrap().1;
+                if pattern.is_empty() {
+                    return Err(format!("empty pattern in bashtestmd tag: {lang}"));
+                }
+                tags.output_matches.push(pattern.to_string());
+            } else if lang.starts_with("bashtestmd:matrix=") {
+                // Already expanded, see `expand_matrices`.
+            } else if lang.starts_with("bashtestmd:ulimit=") {
+                let (limit, value) = lang
+                    .split_once('=')
+                    .unwrap()
+                    .1
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected LIMIT=VALUE in bashtestmd tag: {lang}"))?;
+                match limit.parse().ok().filter(|limit| ULIMITS.contains(limit)) {
+                    Some(limit) => tags.ulimits.push((limit, value.to_string())),
+                    None => warn!(
+                        diagnostics,
+                        "{location}Unknown limit in bashtestmd tag, ignoring: {lang} (supported limits: {})",
+                        ULIMITS.map(String::from).join(", ")
+                    ),
+                }
+            } else if lang.starts_with("bashtestmd:expect-signal=") {
+                let name = lang.split_once('=').unwrap().1.to_uppercase();
+                let name = name.strip_prefix("SIG").unwrap_or(&name);
+                match SIGNALS.iter().find(|(signal, _)| *signal == name) {
+                    // The exit code of a command terminated by a signal.
+                    Some(&(signal, number)) => {
+                        tags.exit_code = Some(ExitCode::Exactly(128 + number));
+                        tags.expect_signal = Some(signal);
+                    }
+                    None => warn!(
+                        diagnostics,
+                        "{location}Unknown signal in bashtestmd tag, ignoring: {lang} (supported signals: {})",
+                        SIGNALS.map(|(signal, _)| signal).join(", ")
+                    ),
+                }
+            } else if lang.starts_with("bashtestmd:group=") {
+                let group = lang.split_once('=').unwrap().1.to_string();
+                tags.group = Some(group);
+            } else if lang.starts_with("bashtestmd:parallel-group=") {
+                let parallel_group = lang.split_once('=').unwrap().1.to_string();
+                tags.parallel_group = Some(parallel_group);
+            } else if lang.starts_with("bashtestmd:capture=") {
+                let variable = lang.split_once('=').unwrap().1;
+                // It's written into the script as is.
+                if !is_variable_name(variable) {
+                    return Err(format!("invalid variable name in tag {lang}"));
+                }
+                tags.capture = Some(variable.to_string());
+            } else if lang.starts_with("bashtestmd:wait-on=") {
+                tags.wait_on = tag_value(&lang)?;
+            } else if lang.starts_with("bashtestmd:") {
+                // Other tags, like the language (e.g. ```rust,test-ci```) or
+                // the ones selecting the block for other scripts, aren't ours.
+                warn!(
+                    diagnostics,
+                    "{location}Unknown bashtestmd tag, ignoring: {lang}"
+                );
+            }
+        }
+
+        Ok(tags)
+    }
+}
+
+/// The value of a `bashtestmd:NAME=VALUE` tag.
+fn tag_value<T>(tag: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = tag.split_once('=').unwrap().1;
+    value
+        .parse()
+        .map_err(|err| format!("invalid value '{value}' in bashtestmd tag {tag} ({err})"))
+}
+
+/// Whether `name` can be the name of a shell variable.
+fn is_variable_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Where a code block is, as the prefix of the diagnostics about it, e.g.
+/// `README.md:142: `. Without a file, only the line is known.
+fn location(file: &str, code_block: &mdast::Code) -> String {
+    let line = code_block
+        .position
+        .as_ref()
+        .map(|position| position.start.line);
+    match (file, line) {
+        ("", Some(line)) => format!("line {line}: "),
+        ("", None) => String::new(),
+        (file, Some(line)) => format!("{file}:{line}: "),
+        (file, None) => format!("{file}: "),
+    }
+}
+
+/// Options for turning code blocks into commands.
+pub struct ConversionOptions {
+    /// Only code blocks with this tag are converted. If it's empty, the code
+    /// blocks in one of `SHELL_LANGUAGES` are.
+    pub tag: String,
+    /// Code blocks with any of these tags aren't converted, even if they're
+    /// selected by `tag`.
+    pub exclude_tags: Vec<String>,
+    /// Values of the placeholders `{{NAME}}` in commands and expected outputs,
+    /// besides `{{TAG}}`.
+    pub defines: Vec<(String, String)>,
+    /// Treat tagged code blocks without commands as errors.
+    pub werror: bool,
+    /// Record the output of commands tagged with `bashtestmd:output-follows`
+    /// for `--embed-output`, instead of comparing it.
+    pub embed_output: bool,
+    /// Lines of code blocks starting with this are commands.
+    pub prompt: String,
+    /// Only print errors, not warnings (which are still counted).
+    pub quiet: bool,
+    /// Also print each code block that is converted.
+    pub verbose: bool,
+    /// Treat warnings as errors.
+    pub strict: bool,
+    /// The Markdown file of the code blocks, for the locations in the
+    /// diagnostics.
+    pub file: String,
+}
+
+impl Default for ConversionOptions {
+    fn default() -> Self {
+        Self {
+            tag: String::new(),
+            exclude_tags: Vec::new(),
+            defines: Vec::new(),
+            werror: false,
+            embed_output: false,
+            prompt: "$ ".to_string(),
+            quiet: false,
+            verbose: false,
+            strict: false,
+            file: String::new(),
+        }
+    }
+}
+
+/// The commands of the code blocks tagged with `options.tag`. Issues with
+/// the blocks are printed, and fail the conversion if they're errors.
+pub fn convert_code_blocks_into_commands(
+    mut code_blocks: Vec<mdast::Code>,
+    options: &ConversionOptions,
+) -> Result<Conversion, ConversionError> {
+    // Documents with CRLF line endings behave like the ones with LF, also in
+    // the blocks that are used as a whole, like expected outputs.
+    for code_block in &mut code_blocks {
+        if code_block.value.contains('\r') {
+            code_block.value = code_block.value.replace("\r\n", "\n");
+        }
+        // E.g. in a list item, the lines of the block may be indented further
+        // than its fence.
+        code_block.value = dedent(&code_block.value, &options.prompt);
+    }
+    let only_tag = options.tag.as_str();
+    let diagnostics = &mut Diagnostics {
+        quiet: options.quiet,
+        warnings: 0,
+    };
+    let mut errors = 0;
+
+    // Without a tag, `{{TAG}}` is left unresolved rather than removed.
+    let variables: Vec<(&str, &str)> = (!only_tag.is_empty())
+        .then_some(("TAG", only_tag))
+        .into_iter()
+        .chain(
+            options
+                .defines
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        )
+        .collect();
+
+    let mut commands = Vec::new();
+    // Commands from blocks tagged with `bashtestmd:only`. If there are any, all
+    // other commands are dropped.
+    let mut focused_commands = Vec::new();
+    let mut any_focused = false;
+    // Commands from blocks tagged with `bashtestmd:setup` and
+    // `bashtestmd:teardown`, which run before and after all others.
+    let mut setup_commands = Vec::new();
+    let mut teardown_commands = Vec::new();
+
+    // Expected outputs defined with `bashtestmd:define-output`, in blocks with
+    // any tag, so that they can be shared between suites. A name can be
+    // defined more than once, e.g. for a step that's repeated, and `NAME#N`
+    // refers to the Nth definition.
+    let mut defined_outputs: HashMap<String, Vec<String>> = HashMap::new();
+    for code_block in &code_blocks {
+        let Some(name) = fence_tokens(code_block)
+            .iter()
+            .find_map(|token| token.strip_prefix("bashtestmd:define-output="))
+            .map(str::to_string)
+        else {
+            continue;
+        };
+        defined_outputs
+            .entry(name)
+            .or_default()
+            .push(format!("{}\n", code_block.value));
+    }
+
+    // By their offset in the file, which the copies of expanded blocks share.
+    let block_indices: HashMap<usize, usize> = code_blocks
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, code_block)| Some((code_block.position.as_ref()?.start.offset, idx)))
+        .collect();
+
+    let mut code_blocks =
+        expand_matrices(code_blocks, only_tag, &options.file, diagnostics).into_iter();
+    while let Some((code_block, params)) = code_blocks.next() {
+        if !is_selected(&code_block, only_tag)
+            || options
+                .exclude_tags
+                .iter()
+                .any(|tag| is_tagged(&code_block, tag))
+        {
+            continue;
+        }
+        let location = location(&options.file, &code_block);
+        if options.verbose {
+            eprintln!(
+                "{location}Converting the block: {}",
+                fence_tokens(&code_block).join(",")
+            );
+        }
+        let mut block_contains_command = false;
+        let tags = match CodeBlockTags::parse(&code_block, &location, diagnostics) {
+            Ok(tags) => tags,
+            Err(err) => {
+                eprintln!("{location}Error: {err}");
+                errors += 1;
+                continue;
+            }
+        };
+        if tags.skip {
+            if options.verbose {
+                eprintln!("{location}Skipping the block tagged with `bashtestmd:skip`");
+            }
+            continue;
+        }
+        if tags.define_output.is_some() {
+            continue;
+        }
+        for conflict in tag_conflicts(&code_block) {
+            eprintln!("{location}Error: {conflict}");
+            errors += 1;
+        }
+        any_focused |= tags.only;
+        let block_commands = if tags.setup {
+            &mut setup_commands
+        } else if tags.teardown {
+            &mut teardown_commands
+        } else if tags.only {
+            &mut focused_commands
+        } else {
+            &mut commands
+        };
+
+        let mut cmd: Option<String> = None;
+        let mut cmd_line = None;
+        let mut output = String::new();
+        // The content of the block starts on the line after the fence.
+        let first_line = code_block
+            .position
+            .as_ref()
+            .map(|position| position.start.line + 1);
+
+        let block = code_block
+            .position
+            .as_ref()
+            .and_then(|position| block_indices.get(&position.start.offset).copied());
+
+        let mut continued = false;
+        // Whether a command of this block was already pushed, after which the
+        // trace of `bashtestmd:trace-to` is appended instead of truncated.
+        let mut pushed_command = false;
+        // Changes the directory before the first command of the block.
+        let mut chdir = tags.chdir.clone();
+        // The whole text of a `bashtestmd:script` block is a single command,
+        // without prompts.
+        let lines = if tags.script {
+            if !code_block.value.trim().is_empty() {
+                cmd = Some(code_block.value.trim_end().to_string());
+                cmd_line = first_line;
+                block_contains_command = true;
+            }
+            ""
+        } else {
+            code_block.value.as_str()
+        };
+        // The heredocs of the command whose lines are still to come.
+        let mut heredocs = VecDeque::new();
+        for (idx, line) in lines.lines().enumerate() {
+            if continued {
+                // The previous line of the command ended with a backslash.
+                let cmd = cmd.as_mut().unwrap();
+                cmd.push('\n');
+                cmd.push_str(line);
+                continued = continues_on_next_line(line);
+                heredocs.extend(heredoc_terminators(line));
+            } else if let Some((terminator, strip_tabs)) = heredocs.front() {
+                // A line of a heredoc, which starts after the line of the
+                // command that opens it.
+                let cmd = cmd.as_mut().unwrap();
+                cmd.push('\n');
+                cmd.push_str(line);
+                let line = if *strip_tabs {
+                    line.trim_start_matches('\t')
+                } else {
+                    line
+                };
+                if line == terminator {
+                    heredocs.pop_front();
+                }
+            } else if let Some(cmd_string) = line
+                .strip_prefix(options.prompt.as_str())
+                // Everything after the command of a `bashtestmd:stdin` block is
+                // its input, even lines that look like commands.
+                .filter(|_| !(tags.stdin && block_contains_command))
+            {
+                if let Some(mut cmd) = cmd {
+                    if tags.strip_comments {
+                        cmd = strip_comments(&cmd);
+                    }
+                    let mut cmd =
+                        Command::new(&substitute_placeholders(&cmd, &variables, diagnostics));
+                    cmd.line = cmd_line;
+                    cmd.block = block;
+                    cmd.env = tags.env.clone();
+                    cmd.env_file = tags.env_file.clone();
+                    cmd.group = tags.group.clone();
+                    cmd.parallel_group = tags.parallel_group.clone();
+                    cmd.trace_to = tags.trace_to.clone();
+                    cmd.trace_append = pushed_command;
+                    cmd.pipefail = tags.pipefail;
+                    cmd.flaky = tags.flaky;
+                    cmd.allow_fail = tags.allow_fail;
+                    cmd.ulimits = tags.ulimits.clone();
+                    cmd.isolated = tags.isolated;
+                    cmd.chdir = chdir.take();
+                    cmd.cwd = tags.cwd.clone();
+                    cmd.retry = tags.retry;
+                    cmd.timeout = tags.timeout;
+                    if let Some(delay) = tags.retry_delay {
+                        cmd.retry_delay = delay;
+                    }
+                    cmd.params = params.clone();
+                    block_commands.push(cmd);
+                    pushed_command = true;
+                }
+                cmd = Some(cmd_string.to_string());
+                cmd_line = first_line.map(|first_line| first_line + idx);
+                block_contains_command = true;
+                continued = continues_on_next_line(cmd_string);
+                heredocs.extend(heredoc_terminators(cmd_string));
+            } else {
+                output.push_str(line);
+                output.push('\n');
+            }
+        }
+        // The text of a block tagged with `stdin` is the input of its command,
+        // while an expected output may still follow with `output-follows`.
+        let stdin = tags.stdin.then(|| std::mem::take(&mut output));
+        let mut match_patterns = Vec::new();
+        for name in &tags.match_patterns {
+            match PATTERNS.iter().find(|(pattern, _)| pattern == name) {
+                Some(&pattern) => match_patterns.push(pattern),
+                None => {
+                    eprintln!(
+                        "{location}Error: unknown pattern in `bashtestmd:match-pattern={name}` (supported patterns: {})",
+                        PATTERNS.map(|(pattern, _)| pattern).join(", ")
+                    );
+                    errors += 1;
+                }
+            }
+        }
+        // The normalizations compare with the text of the block if the expected
+        // output doesn't come from elsewhere.
+        let mut compare_output = tags.compare_output
+            || tags.compare_output_exact
+            || (tags.normalization.filter().is_some() && tags.expected_output_file.is_none());
+        let mut embed_output = None;
+        if let Some(reference) = &tags.use_output {
+            let (name, occurrence) = match reference
+                .rsplit_once('#')
+                .and_then(|(name, occurrence)| Some((name, occurrence.parse::<usize>().ok()?)))
+            {
+                Some((name, occurrence)) => (name, Some(occurrence)),
+                None => (reference.as_str(), None),
+            };
+            let definitions = defined_outputs
+                .get(name)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            let defined_output = match occurrence {
+                Some(occurrence) => occurrence
+                    .checked_sub(1)
+                    .and_then(|idx| definitions.get(idx)),
+                None => {
+                    if definitions.len() > 1 {
+                        warn!(diagnostics, "{location}Warning: the output '{name}' is defined {} times, using the first definition. Use `bashtestmd:use-output={name}#N` to use the Nth one", definitions.len());
+                    }
+                    definitions.first()
+                }
+            };
+            match defined_output {
+                Some(defined_output) => {
+                    output = defined_output.clone();
+                    compare_output = true;
+                }
+                None if definitions.is_empty() => {
+                    eprintln!("{location}Error: the output '{name}' is not defined, add a block tagged with `bashtestmd:define-output={name}`");
+                    errors += 1;
+                }
+                None => {
+                    eprintln!("{location}Error: there's no output '{reference}', '{name}' is only defined {} time(s)", definitions.len());
+                    errors += 1;
+                }
+            }
+        }
+        if tags.output_follows {
+            // The next block holds the expected output, so it's not a block with
+            // commands even if it happens to be tagged.
+            match code_blocks.next() {
+                Some((output_block, _)) if options.embed_output => {
+                    embed_output = output_block.position;
+                }
+                Some((output_block, _)) => {
+                    output = output_block.value;
+                    output.push('\n');
+                    compare_output = true;
+                }
+                None => warn!(
+                    diagnostics,
+                    "{location}Warning: no code block with the expected output follows the block tagged with `bashtestmd:output-follows`"
+                ),
+            }
+        }
+
+        if tags.expected_output_file.is_some() && (compare_output || tags.compare_stderr) {
+            eprintln!("{location}Error: a block tagged with `bashtestmd:expected-output-file` can't have another expected output");
+            errors += 1;
+        }
+
+        if !block_contains_command && !tags.expect_no_command {
+            let severity = if options.werror { "Error" } else { "Warning" };
+            let message = format!(
+                "{location}{severity}: could not find command in block:\n```\n{}\n```\n^^^^^ {}, add a command beginning with `{}` or add the tag `bashtestmd:expect-no-command` to fix this {}",
+                &code_block.value,
+                if only_tag.is_empty() {
+                    "change the language of the block".to_string()
+                } else {
+                    format!("remove the tag {only_tag} from the block")
+                },
+                options.prompt,
+                severity.to_lowercase()
+            );
+            if options.werror {
+                errors += 1;
+                eprintln!("{message}");
+            } else {
+                warn!(diagnostics, "{message}");
+            }
+        }
+        if let Some(mut cmd) = cmd {
+            if tags.strip_comments {
+                cmd = strip_comments(&cmd);
+            }
+            let mut cmd = Command::new(&substitute_placeholders(&cmd, &variables, diagnostics));
+            cmd.line = cmd_line;
+            cmd.block = block;
+            cmd.long_running = tags.long_running;
+            cmd.exit_code = tags.exit_code;
+            cmd.wait_until = tags.wait_until;
+            cmd.wait_until_regex = tags.wait_until_regex;
+            if let Some(interval) = tags.wait_until_interval {
+                cmd.wait_interval = interval;
+            }
+            cmd.wait_timeout = tags.wait_until_timeout;
+            cmd.background_delay = tags.background_delay;
+            cmd.daemon = tags.daemon;
+            cmd.wait_on = tags.wait_on;
+            cmd.expect_diff = tags.expect_diff;
+            cmd.trace_to = tags.trace_to;
+            cmd.trace_append = pushed_command;
+            cmd.expect_sha256 = tags.expect_sha256;
+            cmd.expect_no_output_change = tags.expect_no_output_change;
+            cmd.expect_output_unchanged_across = tags.expect_output_unchanged_across;
+            cmd.show_output = tags.show_output;
+            cmd.expect_output_empty_lines = tags.expect_output_empty_lines;
+            cmd.pipefail = tags.pipefail;
+            cmd.env = tags.env;
+            cmd.env_file = tags.env_file;
+            cmd.cache_key = tags.cache_key;
+            cmd.expect_output_matches_command = tags.expect_output_matches_command;
+            cmd.max_capture_bytes = tags.max_capture_bytes;
+            cmd.retry_on_output = tags.retry_on_output;
+            cmd.stdin_file = tags.stdin_file;
+            cmd.stdin = stdin.map(|stdin| substitute_placeholders(&stdin, &variables, diagnostics));
+            cmd.expect_schema = tags.expect_schema;
+            cmd.expected_output_file = tags.expected_output_file;
+            cmd.expect_no_file = tags.expect_no_file;
+            cmd.expect_files_equal = tags.expect_files_equal;
+            cmd.asserts = tags.asserts;
+            cmd.match_patterns = match_patterns;
+            cmd.output_matches = tags.output_matches;
+            cmd.group = tags.group;
+            cmd.parallel_group = tags.parallel_group;
+            cmd.capture = tags.capture;
+            cmd.expand_expected = tags.expand_expected;
+            cmd.strip_ansi = tags.strip_ansi;
+            cmd.exact_output = tags.compare_output_exact;
+            cmd.normalization = tags.normalization;
+            cmd.flaky = tags.flaky;
+            cmd.allow_fail = tags.allow_fail;
+            cmd.ulimits = tags.ulimits;
+            cmd.expect_signal = tags.expect_signal;
+            cmd.isolated = tags.isolated;
+            cmd.script = tags.script;
+            cmd.chdir = chdir;
+            cmd.cwd = tags.cwd;
+            cmd.params = params;
+            cmd.embed_output = embed_output;
+            if let Some(attempts) = tags.retry_attempts {
+                cmd.retry_attempts = attempts;
+            }
+            cmd.retry = tags.retry;
+            cmd.timeout = tags.timeout;
+            if let Some(delay) = tags.retry_delay {
+                cmd.retry_delay = delay;
+            }
+            // Command substitution strips the trailing newlines of the output.
+            let unexpected = output.trim_end_matches('\n');
+            cmd.unexpected_output = if !tags.output_not_contains {
+                None
+            } else if unexpected.is_empty() {
+                warn!(diagnostics, "{location}Warning: the block tagged with `bashtestmd:output-not-contains` has no text that must not be in the output, ignoring the tag");
+                None
+            } else {
+                Some(substitute_placeholders(unexpected, &variables, diagnostics))
+            };
+            cmd.expected_json = if tags.expect_output_json_eq {
+                Some(substitute_placeholders(&output, &variables, diagnostics))
+            } else {
+                None
+            };
+            // The expected output of a block tagged with `compare-stderr` is the
+            // expected standard error. Without trailing newlines, which command
+            // substitution strips.
+            cmd.expected_stderr = if tags.compare_stderr {
+                Some(substitute_placeholders(
+                    output.trim_end_matches('\n'),
+                    &variables,
+                    diagnostics,
+                ))
+            } else {
+                None
+            };
+            cmd.expected_output = if compare_output && !tags.compare_stderr {
+                Some(substitute_placeholders(&output, &variables, diagnostics))
+            } else {
+                None
+            };
+            block_commands.push(cmd);
+        }
+    }
+
+    if any_focused && errors == 0 {
+        warn!(diagnostics, "Warning: some blocks are tagged with `bashtestmd:only`, all other blocks are ignored!\n^^^^^ remove the `bashtestmd:only` tag before committing");
+        commands = focused_commands;
+    }
+
+    let warnings = diagnostics.warnings;
+    if options.strict && warnings > 0 {
+        eprintln!("Error: {warnings} warning(s), which are errors with --strict");
+        errors += warnings;
+    }
+    if errors > 0 {
+        return Err(ConversionError { errors, warnings });
+    }
+
+    for cmd in &mut teardown_commands {
+        cmd.teardown = true;
+    }
+    setup_commands.append(&mut commands);
+    setup_commands.append(&mut teardown_commands);
+    Ok(Conversion {
+        commands: setup_commands,
+        warnings,
+    })
+}
+
+/// Removes the indentation of the first command of `text`, i.e. its first
+/// line starting with `prompt`, as far as all non-blank lines have it. The
+/// indentation of lines relative to each other stays, e.g. in heredocs, and
+/// text without commands, like an expected output, is left as it is.
+fn dedent(text: &str, prompt: &str) -> String {
+    let indentation = |line: &str| line.len() - line.trim_start_matches([' ', '\t']).len();
+    let Some(command_indent) = text
+        .lines()
+        .find(|line| line.trim_start_matches([' ', '\t']).starts_with(prompt))
+        .map(indentation)
+    else {
+        return text.to_string();
+    };
+    let indent = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(indentation)
+        .fold(command_indent, usize::min);
+    if indent == 0 {
+        return text.to_string();
+    }
+    let mut dedented = String::new();
+    for line in text.split_inclusive('\n') {
+        // Blank lines may be shorter than the indentation.
+        dedented.push_str(
+            line.get(indent..)
+                .unwrap_or(line.trim_start_matches([' ', '\t'])),
+        );
+    }
+    dedented
+}
+
+/// Drops the lines of `cmd` that are only comments, e.g. notes for the readers
+/// of a `bashtestmd:script` block, but keeps a `#!` line at the start. The
+/// lines of heredocs are text rather than code, and are kept as they are.
+fn strip_comments(cmd: &str) -> String {
+    let mut lines: Vec<&str> = Vec::new();
+    let mut heredocs = VecDeque::new();
+    for (idx, line) in cmd.lines().enumerate() {
+        if let Some((terminator, strip_tabs)) = heredocs.front() {
+            lines.push(line);
+            let line = if *strip_tabs {
+                line.trim_start_matches('\t')
+            } else {
+                line
+            };
+            if line == terminator {
+                heredocs.pop_front();
+            }
+            continue;
+        }
+        let is_shebang = idx == 0 && line.starts_with("#!");
+        if is_shebang || !line.trim_start().starts_with('#') {
+            lines.push(line);
+            heredocs.extend(heredoc_terminators(line));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Whether `line` ends with a backslash that continues the command on the
+/// next line. Backslashes in single quotes are literal, and an escaped
+/// backslash (`\\`) doesn't continue the command.
+fn continues_on_next_line(line: &str) -> bool {
+    let mut in_single_quotes = false;
+    let mut in_double_quotes = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' if !in_double_quotes => in_single_quotes = !in_single_quotes,
+            '"' if !in_single_quotes => in_double_quotes = !in_double_quotes,
+            // Skips the escaped character, if there's one.
+            '\\' if !in_single_quotes && chars.next().is_none() => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// The terminators of the heredocs that `line` opens, e.g. `EOF` for
+/// `cat <<EOF`, and whether the lines of each may be indented with tabs, as
+/// with `<<-EOF`. Quotes in the terminator (e.g. `<<'EOF'`) aren't part of it.
+fn heredoc_terminators(line: &str) -> Vec<(String, bool)> {
+    let mut terminators = Vec::new();
+    let mut in_single_quotes = false;
+    let mut in_double_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' if !in_double_quotes => in_single_quotes = !in_single_quotes,
+            '"' if !in_single_quotes => in_double_quotes = !in_double_quotes,
+            '\\' if !in_single_quotes => {
+                chars.next();
+            }
+            '<' if !in_single_quotes && !in_double_quotes && chars.peek() == Some(&'<') => {
+                chars.next();
+                // A here-string, e.g. `<<<"text"`.
+                if chars.next_if_eq(&'<').is_some() {
+                    continue;
+                }
+                let strip_tabs = chars.next_if_eq(&'-').is_some();
+                while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+                // Rather than e.g. a shift like `$((1 << 2))`.
+                if !chars
+                    .peek()
+                    .is_some_and(|c| c.is_alphabetic() || matches!(c, '_' | '\'' | '"' | '\\'))
+                {
+                    continue;
+                }
+                let mut terminator = String::new();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !";|&<>()".contains(*c))
+                {
+                    match c {
+                        '\'' | '"' => {
+                            while let Some(quoted) = chars.next_if(|quoted| *quoted != c) {
+                                terminator.push(quoted);
+                            }
+                            chars.next();
+                        }
+                        '\\' => terminator.extend(chars.next()),
+                        c => terminator.push(c),
+                    }
+                }
+                if !terminator.is_empty() {
+                    terminators.push((terminator, strip_tabs));
+                }
+            }
+            _ => {}
+        }
+    }
+    terminators
+}
+
+/// The most commands a block is expanded into by its `bashtestmd:matrix`
+/// tags.
+const MAX_MATRIX_COMBINATIONS: usize = 64;
+
+/// Replaces the code blocks tagged with `only_tag` and `bashtestmd:matrix`
+/// with one copy per combination of the values of their variables, in which
+/// the `{{VAR}}` placeholders are replaced with the values. The block with the
+/// output of a block tagged with `bashtestmd:output-follows` is copied along.
+/// Each block comes with the values it was expanded with, if any.
+fn expand_matrices(
+    code_blocks: Vec<mdast::Code>,
+    only_tag: &str,
+    file: &str,
+    diagnostics: &mut Diagnostics,
+) -> Vec<(mdast::Code, Option<String>)> {
+    let mut expanded = Vec::new();
+    let mut code_blocks = code_blocks.into_iter();
+    while let Some(code_block) = code_blocks.next() {
+        let tokens = fence_tokens(&code_block);
+        let matrix: Vec<(&str, Vec<&str>)> = tokens
+            .iter()
+            .filter_map(|token| token.strip_prefix("bashtestmd:matrix="))
+            .filter_map(|matrix| match matrix.split_once(':') {
+                Some((var, values)) => Some((var, values.split(',').collect())),
+                None => {
+                    warn!(
+                        diagnostics,
+                        "{}Invalid matrix in bashtestmd tag, ignoring: {matrix} (expected VAR:VALUE,VALUE,...)",
+                        location(file, &code_block)
+                    );
+                    None
+                }
+            })
+            .collect();
+        if matrix.is_empty() || !is_selected(&code_block, only_tag) {
+            expanded.push((code_block, None));
+            continue;
+        }
+        let output_block = if tokens
+            .iter()
+            .any(|token| token == "bashtestmd:output-follows")
+        {
+            code_blocks.next()
+        } else {
+            None
+        };
+
+        // The first variable changes the slowest.
+        let mut combinations: Vec<Vec<(&str, &str)>> = vec![Vec::new()];
+        for (var, values) in &matrix {
+            combinations = combinations
+                .iter()
+                .flat_map(|combination| {
+                    values.iter().map(|value| {
+                        let mut combination = combination.clone();
+                        combination.push((*var, *value));
+                        combination
+                    })
+                })
+                .collect();
+        }
+        if combinations.len() > MAX_MATRIX_COMBINATIONS {
+            warn!(
+                diagnostics,
+                "Warning: the matrix of the block{} has {} combinations, only running the first {MAX_MATRIX_COMBINATIONS}",
+                code_block
+                    .position
+                    .as_ref()
+                    .map(|position| format!(" at line {}", position.start.line))
+                    .unwrap_or_default(),
+                combinations.len()
+            );
+            combinations.truncate(MAX_MATRIX_COMBINATIONS);
+        }
+
+        for combination in combinations {
+            let substitute = |block: &mdast::Code| {
+                let mut block = block.clone();
+                for (var, value) in &combination {
+                    block.value = block.value.replace(&format!("{{{{{var}}}}}"), value);
+                }
+                block
+            };
+            let params: Vec<String> = combination
+                .iter()
+                .map(|(var, value)| format!("{var}={value}"))
+                .collect();
+            expanded.push((substitute(&code_block), Some(params.join(", "))));
+            if let Some(output_block) = &output_block {
+                expanded.push((substitute(output_block), None));
+            }
+        }
+    }
+    expanded
+}
+
+/// Makes every command that has no output checks of its own compare its output
+/// against a baseline in `baseline_dir`, which is recorded on the first run.
+pub fn assign_baselines(commands: &mut [Command], baseline_dir: &Path) {
+    let mut occurrences = HashMap::<String, usize>::new();
+
+    for cmd in commands {
+        if cmd.long_running || cmd.captures_output() {
+            continue;
+        }
+        // The name only depends on the command itself, so that it stays the
+        // same when the document changes around it.
+        let mut name = baseline_name(&cmd.cmd);
+        let occurrence = occurrences.entry(name.clone()).or_default();
+        *occurrence += 1;
+        if *occurrence > 1 {
+            name = format!("{name}-{occurrence}");
+        }
+        let path = baseline_dir.join(format!("{name}.out"));
+        cmd.expect_diff = Some(path.display().to_string());
+        cmd.record_missing_baseline = true;
+    }
+}
+
+/// A file name for the baseline of `cmd`: a readable prefix of the command,
+/// followed by a hash of all of it.
+fn baseline_name(cmd: &str) -> String {
+    let slug: String = cmd
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(40)
+        .collect();
+    let slug = slug
+        .split('-')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+
+    // FNV-1a, which unlike `DefaultHasher` is stable across Rust releases.
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in cmd.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    format!("{slug}-{hash:016x}")
+}
+
+/// Parses the contents of a `--commands-file`: every line is a command, except
+/// for blank lines and `#` comments.
+pub fn read_commands_file(file_contents: &str) -> Vec<Command> {
+    file_contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(Command::new)
+        .collect()
+}
+
+/// Replaces `{{NAME}}` placeholders in `text` with the matching values from
+/// `variables`, warning about any placeholder that is left unresolved.
+fn substitute_placeholders(
+    text: &str,
+    variables: &[(&str, &str)],
+    diagnostics: &mut Diagnostics,
+) -> String {
+    let mut result = text.to_string();
+    for (name, value) in variables {
+        result = result.replace(&format!("{{{{{name}}}}}"), value);
+    }
+
+    let mut rest = result.as_str();
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            warn!(
+                diagnostics,
+                "Warning: unresolved placeholder, leaving as is: {{{{{name}}}}}"
+            );
+        }
+        rest = &rest[start + 2..];
+    }
+
+    result
+}
+
+/// Ordered list of all code blocks in the Markdown file, with the tags of
+/// the directives preceding them added to their fences, see
+/// `with_directives`.
+pub fn get_all_code_blocks(markdown_ast: mdast::Node) -> Vec<mdast::Code> {
+    let mut code_blocks = Vec::new();
+
+    let mut nodes: VecDeque<mdast::Node> = with_directives(
+        markdown_ast
+            .children()
+            .map(Vec::as_slice)
+            .unwrap_or_default(),
+    )
+    .into();
+
+    while let Some(next_node) = nodes.pop_front() {
+        if let mdast::Node::Code(code_node) = next_node {
+            code_blocks.push(code_node);
+        } else {
+            let children =
+                with_directives(next_node.children().map(Vec::as_slice).unwrap_or_default());
+            // In reverse, so that the first child ends up in front and the
+            // blocks stay in the order of the document.
+            for child in children.into_iter().rev() {
+                nodes.push_front(child);
+            }
+        }
+    }
+
+    code_blocks
+}
+
+/// Copies sibling nodes, adding the tags of a directive like
+/// `<!-- bashtestmd: exit-code=1, wait-until=Listening -->` to the fence of
+/// the code block right after it, which keeps the tags out of the rendered
+/// document. The tags of the directive take precedence over the ones of the
+/// fence with the same name, except for `REPEATABLE_TAGS`, which add up.
+fn with_directives(nodes: &[mdast::Node]) -> Vec<mdast::Node> {
+    let mut copied: Vec<mdast::Node> = nodes.to_vec();
+    for idx in 1..copied.len() {
+        let mdast::Node::Html(html) = &copied[idx - 1] else {
+            continue;
+        };
+        let Some(tags) = directive_tags(&html.value) else {
+            continue;
+        };
+        let mdast::Node::Code(code_block) = &mut copied[idx] else {
+            continue;
+        };
+        let overridden: Vec<&str> = tags
+            .iter()
+            .filter_map(|tag| tag_name(tag))
+            .filter(|name| !REPEATABLE_TAGS.contains(name))
+            .collect();
+        let info = match &code_block.meta {
+            Some(meta) => format!("{} {meta}", code_block.lang.as_deref().unwrap_or_default()),
+            None => code_block.lang.clone().unwrap_or_default(),
+        };
+        let mut tokens: Vec<String> = split_at_commas(&info)
+            .into_iter()
+            .filter(|token| !tag_name(token).is_some_and(|name| overridden.contains(&name)))
+            .collect();
+        tokens.extend(tags.iter().cloned());
+        // The whole info string, which `fence_tokens` splits at commas only.
+        code_block.lang = Some(tokens.join(","));
+        code_block.meta = None;
+    }
+    copied
+}
+
+/// The name of a `bashtestmd:` tag as it's in a fence, e.g. `exit-code` for
+/// `bashtestmd:exit-code=1`.
+fn tag_name(token: &str) -> Option<&str> {
+    let tag = token.trim().strip_prefix("bashtestmd:")?;
+    Some(tag.split_once('=').map_or(tag, |(name, _)| name))
+}
+
+/// Splits `text` at the commas that aren't in double quotes, keeping the
+/// quotes, e.g. `a,"b,c"` into `a` and `"b,c"`.
+fn split_at_commas(text: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut in_quotes = false;
+    for c in text.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                parts.last_mut().unwrap().push(c);
+            }
+            ',' if !in_quotes => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+    parts
+}
+
+/// The tags of a `<!-- bashtestmd: ... -->` directive as they would be in a
+/// fence, e.g. `bashtestmd:exit-code=1` and `bashtestmd:wait-until=Listening`.
+/// Commas in double quotes don't separate tags, like in fences.
+fn directive_tags(html: &str) -> Option<Vec<String>> {
+    let directive = html
+        .trim()
+        .strip_prefix("<!--")?
+        .strip_suffix("-->")?
+        .trim()
+        .strip_prefix("bashtestmd:")?;
+    // Directives of their own, for the whole document.
+    let first_word = directive.split_whitespace().next().unwrap_or_default();
+    if first_word == "config" || first_word == "include" {
+        return None;
+    }
+
+    let tags: Vec<String> = split_at_commas(directive)
+        .iter()
+        .map(|tag| tag.trim())
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| format!("bashtestmd:{tag}"))
+        .collect();
+    (!tags.is_empty()).then_some(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Converts the code blocks of `markdown` tagged with `test-ci`.
+    fn convert(markdown: &str) -> Result<Conversion, ConversionError> {
+        let ast = markdown::to_mdast(markdown, &markdown::ParseOptions::gfm()).unwrap();
+        let options = ConversionOptions {
+            tag: "test-ci".to_string(),
+            quiet: true,
+            ..Default::default()
+        };
+        convert_code_blocks_into_commands(get_all_code_blocks(ast), &options)
+    }
+
+    /// The commands of the code blocks of `markdown` tagged with `test-ci`.
+    fn commands(markdown: &str) -> Vec<Command> {
+        convert(markdown).unwrap().commands
+    }
+
+    /// The script of the code blocks of `markdown` tagged with `test-ci`.
+    fn script(markdown: &str, options: &ScriptOptions) -> String {
+        compile_commands_into_bash(commands(markdown), options).0
+    }
+
+    /// Runs `script` with bash, returning its exit code and its output.
+    fn run(script: &str) -> (i32, String) {
+        let output = std::process::Command::new("bash")
+            .args(["-c", script])
+            .stdin(std::process::Stdio::null())
+            .output()
+            .unwrap();
+        let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+        text.push_str(&String::from_utf8_lossy(&output.stderr));
+        (output.status.code().unwrap_or(-1), text)
+    }
+
+    #[test]
+    fn pipefail_applies_to_every_command_of_the_block() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:pipefail
+            $ false | cat
+            $ echo done
+            ```
+        "};
+        let script = script(markdown, &ScriptOptions::default());
+        assert_eq!(script.matches("set -o pipefail").count(), 2);
+        let (code, output) = run(&script);
+        assert_eq!(code, 1, "{output}");
+        assert!(!output.contains("Running: 'echo done'"), "{output}");
+    }
+
+    #[test]
+    fn pipelines_pass_if_their_last_stage_does_without_pipefail() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ false | cat
+            ```
+        "};
+        let script = script(markdown, &ScriptOptions::default());
+        assert!(!script.contains("set -o pipefail"));
+        assert_eq!(run(&script).0, 0);
+    }
+
+    #[test]
+    fn flaky_failures_of_any_command_of_the_block_are_quarantined() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:flaky
+            $ false
+            $ echo second
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+        assert!(
+            output.contains("FLAKY FAILURE (quarantined): 'false'"),
+            "{output}"
+        );
+        assert!(output.contains("Running: 'echo second'"), "{output}");
+        assert!(output.contains("All tests passed!"), "{output}");
+    }
+
+    #[test]
+    fn allowed_failures_warn_and_carry_on() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:allow-fail
+            $ false
+            $ echo second
+            ```
+
+            ```sh,test-ci
+            $ echo after
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+        assert!(
+            output.contains("Warning: 'false' failed, carrying on (allowed to fail)"),
+            "{output}"
+        );
+        assert!(output.contains("Running: 'echo second'"), "{output}");
+        assert!(output.contains("Running: 'echo after'"), "{output}");
+        assert!(!output.contains("quarantined"), "{output}");
+    }
+
+    #[test]
+    fn allowed_output_mismatches_warn_and_carry_on() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:allow-fail,bashtestmd:compare-output
+            $ echo actual
+            expected
+            ```
+
+            ```sh,test-ci
+            $ echo after
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+        assert!(
+            output.contains("Warning: 'echo actual' failed, carrying on (allowed to fail)"),
+            "{output}"
+        );
+        assert!(output.contains("Running: 'echo after'"), "{output}");
+    }
+
+    #[test]
+    fn ignored_exit_codes_still_check_the_output() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:exit-code-ignore,bashtestmd:compare-output
+            $ echo "partial results"; exit 3
+            partial results
+            ```
+        "#};
+        let passing = script(markdown, &ScriptOptions::default());
+        assert!(!passing.contains("Expected exit code"));
+        assert_eq!(run(&passing).0, 0);
+
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:exit-code-ignore,bashtestmd:compare-output
+            $ echo other; exit 3
+            partial results
+            ```
+        "};
+        assert_eq!(run(&script(markdown, &ScriptOptions::default())).0, 2);
+    }
+
+    #[test]
+    fn asserts_see_the_ignored_exit_code() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci
+            $ true
+            ```
+
+            ```sh,test-ci,bashtestmd:exit-code-ignore,bashtestmd:assert="[ $exit_code -eq 3 ]"
+            $ (exit 3)
+            ```
+        "#};
+        let script = script(markdown, &ScriptOptions::default());
+        assert_eq!(script.matches("exit_code=$?").count(), 2);
+        let (code, output) = run(&script);
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn indented_commands_in_list_items_are_found() {
+        let markdown = indoc! {"
+            1. Build it:
+               ```sh,test-ci,bashtestmd:compare-output
+                 $ printf 'a\\n  b\\n'
+                 a
+                   b
+               ```
+        "};
+        let commands = commands(markdown);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].cmd, r"printf 'a\n  b\n'");
+        assert_eq!(commands[0].expected_output.as_deref(), Some("a\n  b\n"));
+    }
+
+    #[test]
+    fn indented_expected_outputs_keep_their_indentation() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:output-follows
+            $ printf '  a\n  b\n'
+            ```
+
+            ```text
+              a
+              b
+            ```
+        "#};
+        let commands = commands(markdown);
+        assert_eq!(commands[0].expected_output.as_deref(), Some("  a\n  b\n"));
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn indented_stdin_keeps_its_indentation() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:stdin
+            $ cat
+              indented
+            ```
+        "};
+        assert_eq!(commands(markdown)[0].stdin.as_deref(), Some("  indented\n"));
+    }
+
+    #[test]
+    fn keep_going_leaves_the_directory_of_a_failed_cd_block() {
+        let dir = std::env::temp_dir().join(format!("bashtestmd-cd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let markdown = format!(
+            indoc! {"
+                ```sh,test-ci,bashtestmd:cd={}
+                $ false
+                ```
+
+                ```sh,test-ci
+                $ pwd
+                ```
+            "},
+            dir.display()
+        );
+        let options = ScriptOptions {
+            keep_going: true,
+            ..Default::default()
+        };
+        let (code, output) = run(&script(&markdown, &options));
+        std::fs::remove_dir(&dir).unwrap();
+        assert_eq!(code, 1, "{output}");
+        assert!(output.contains("1 of 2 commands failed"), "{output}");
+        let cwd = std::env::current_dir().unwrap();
+        assert!(
+            output.lines().any(|line| Path::new(line) == cwd),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn failed_attempts_dont_report_the_summary() {
+        let counter = std::env::temp_dir().join(format!("bashtestmd-retry-{}", std::process::id()));
+        let markdown = format!(
+            indoc! {r#"
+                ```sh,test-ci,bashtestmd:retry=2,bashtestmd:retry-delay=0,bashtestmd:group=G
+                $ echo attempt >> {0}; [ $(wc -l < {0}) -ge 2 ]
+                ```
+            "#},
+            counter.display()
+        );
+        let options = ScriptOptions {
+            preserve_temp: true,
+            ..Default::default()
+        };
+        let (code, output) = run(&script(&markdown, &options));
+        std::fs::remove_file(&counter).unwrap();
+        assert_eq!(code, 0, "{output}");
+        assert!(output.contains("Attempt 1 of 2 failed"), "{output}");
+        assert_eq!(output.matches("Summary by group:").count(), 1, "{output}");
+        assert_eq!(
+            output.matches("Preserved temporary files:").count(),
+            1,
+            "{output}"
+        );
+        assert!(output.contains("G: 1/1 passed"), "{output}");
+    }
+
+    #[test]
+    fn directives_take_precedence_over_the_fence() {
+        let markdown = indoc! {r#"
+            <!-- bashtestmd: exit-code=3, env=B=2 -->
+            ```sh,test-ci,bashtestmd:exit-code=1,bashtestmd:env=A=1
+            $ (exit 3)
+            ```
+        "#};
+        let commands = commands(markdown);
+        assert_eq!(commands[0].exit_code, Some(ExitCode::Exactly(3)));
+        assert_eq!(commands[0].env.len(), 2);
+    }
+
+    #[test]
+    fn directives_contradicting_the_fence_are_errors() {
+        let markdown = indoc! {"
+            <!-- bashtestmd: long-running -->
+            ```sh,test-ci,bashtestmd:compare-output
+            $ echo hello
+            hello
+            ```
+        "};
+        assert_eq!(convert(markdown).err().unwrap().errors, 1);
+    }
+
+    #[test]
+    fn different_values_in_the_fence_are_errors() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:exit-code=1,bashtestmd:exit-code=2
+            $ false
+            ```
+        "};
+        assert_eq!(convert(markdown).err().unwrap().errors, 1);
+    }
+
+    #[test]
+    fn strip_comments_drops_comment_lines_but_keeps_the_shebang_line() {
+        assert_eq!(
+            strip_comments(
+                "#!/usr/bin/env bash\n# Say hello.\necho hello # to the world\n  # Then leave.\nexit 0"
+            ),
+            "#!/usr/bin/env bash\necho hello # to the world\nexit 0"
+        );
+        assert_eq!(
+            strip_comments("echo hello\n#!/usr/bin/env bash"),
+            "echo hello"
+        );
+    }
+
+    #[test]
+    fn strip_comments_drops_comment_lines_but_keeps_the_shebang() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:script,bashtestmd:strip-comments
+            #!/usr/bin/env bash
+            # Say hello.
+            echo hello # to the world
+              # Then leave.
+            exit 0
+            ```
+        "};
+        let commands = commands(markdown);
+        assert_eq!(
+            commands[0].cmd,
+            "#!/usr/bin/env bash\necho hello # to the world\nexit 0"
+        );
+    }
+
+    #[test]
+    fn comments_are_kept_without_strip_comments() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:script
+            # Say hello.
+            echo hello
+            ```
+        "};
+        assert!(commands(markdown)[0].cmd.contains("# Say hello."));
+    }
+
+    #[test]
+    fn captured_outputs_expand_in_later_expected_outputs() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:capture=ADDRESS
+            $ echo 0xabc
+            ```
+
+            ```sh,test-ci,bashtestmd:compare-output,bashtestmd:expand-expected
+            $ echo "Sent 10 tokens to $ADDRESS"
+            Sent 10 tokens to $ADDRESS
+            ```
+        "#};
+        let passing = script(markdown, &ScriptOptions::default());
+        assert!(passing.contains("ADDRESS="), "{passing}");
+        let (code, output) = run(&passing);
+        assert_eq!(code, 0, "{output}");
+
+        let failing = markdown.replace(
+            "Sent 10 tokens to $ADDRESS\n```",
+            "Sent 10 tokens to 0xdef\n```",
+        );
+        let (code, output) = run(&script(&failing, &ScriptOptions::default()));
+        assert_eq!(code, 2, "{output}");
+    }
+
+    #[test]
+    fn indexed_references_select_the_nth_definition() {
+        let markdown = indoc! {"
+            ```text,bashtestmd:define-output=greeting
+            hello
+            ```
+
+            ```text,bashtestmd:define-output=greeting
+            goodbye
+            ```
+
+            ```sh,test-ci,bashtestmd:use-output=greeting#2
+            $ echo goodbye
+            ```
+        "};
+        let conversion = convert(markdown).unwrap();
+        assert_eq!(conversion.warnings, 0);
+        assert_eq!(
+            conversion.commands[0].expected_output.as_deref(),
+            Some("goodbye\n")
+        );
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn unqualified_references_to_duplicate_names_use_the_first_with_a_warning() {
+        let markdown = indoc! {"
+            ```text,bashtestmd:define-output=greeting
+            hello
+            ```
+
+            ```text,bashtestmd:define-output=greeting
+            goodbye
+            ```
+
+            ```sh,test-ci,bashtestmd:use-output=greeting
+            $ echo hello
+            ```
+        "};
+        let conversion = convert(markdown).unwrap();
+        assert_eq!(conversion.warnings, 1);
+        assert_eq!(
+            conversion.commands[0].expected_output.as_deref(),
+            Some("hello\n")
+        );
+    }
+
+    #[test]
+    fn references_past_the_last_definition_are_errors() {
+        let markdown = indoc! {"
+            ```text,bashtestmd:define-output=greeting
+            hello
+            ```
+
+            ```sh,test-ci,bashtestmd:use-output=greeting#2
+            $ echo hello
+            ```
+        "};
+        assert_eq!(convert(markdown).err().unwrap().errors, 1);
+    }
+
+    #[test]
+    fn quoted_tag_values_keep_their_commas() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:long-running,bashtestmd:wait-until="Server started, listening",bashtestmd:env="GREETING=hello, world"
+            $ echo "Server started, listening"
+            ```
+        "#};
+        let commands = commands(markdown);
+        assert_eq!(
+            commands[0].wait_until.as_deref(),
+            Some("Server started, listening")
+        );
+        assert_eq!(
+            commands[0].env,
+            [("GREETING".to_string(), "hello, world".to_string())]
+        );
+    }
+
+    #[test]
+    fn unquoted_commas_separate_tags() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:long-running,bashtestmd:wait-until=Server started,bashtestmd:exit-code=1
+            $ echo Server started
+            ```
+        "};
+        let commands = commands(markdown);
+        assert_eq!(commands[0].wait_until.as_deref(), Some("Server started"));
+        assert_eq!(commands[0].exit_code, Some(ExitCode::Exactly(1)));
+    }
+
+    #[test]
+    fn two_line_continuations_are_one_command() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:compare-output
+            $ echo hello \
+                world
+            hello world
+            ```
+        "#};
+        let commands = commands(markdown);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].cmd, "echo hello \\\n    world");
+        assert_eq!(
+            commands[0].expected_output.as_deref(),
+            Some("hello world\n")
+        );
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn three_line_continuations_are_one_command() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:compare-output
+            $ printf '%s-%s\n' \
+                first \
+                second
+            first-second
+            ```
+        "#};
+        let commands = commands(markdown);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(
+            commands[0].cmd,
+            "printf '%s-%s\\n' \\\n    first \\\n    second"
+        );
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn backslashes_in_single_quotes_dont_continue_the_command() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:compare-output
+            $ echo 'a \'
+            a \
+            ```
+        "#};
+        let commands = commands(markdown);
+        assert_eq!(commands[0].cmd, "echo 'a \\'");
+        assert_eq!(commands[0].expected_output.as_deref(), Some("a \\\n"));
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn nested_code_blocks_are_in_document_order() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ echo 1
+            ```
+
+            1. First
+
+               ```sh,test-ci
+               $ echo 2
+               ```
+
+               ```sh,test-ci
+               $ echo 3
+               ```
+
+            2. Second
+
+               > ```sh,test-ci
+               > $ echo 4
+               > ```
+               >
+               > ```sh,test-ci
+               > $ echo 5
+               > ```
+
+            ```sh,test-ci
+            $ echo 6
+            ```
+
+            > ```sh,test-ci
+            > $ echo 7
+            > ```
+        "};
+        let cmds: Vec<String> = commands(markdown).into_iter().map(|cmd| cmd.cmd).collect();
+        assert_eq!(
+            cmds,
+            ["echo 1", "echo 2", "echo 3", "echo 4", "echo 5", "echo 6", "echo 7"]
+        );
+    }
+
+    #[test]
+    fn output_not_contains_passes_without_the_text() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:output-not-contains
+            $ echo all good
+            ERROR
+            ```
+        "};
+        let commands = commands(markdown);
+        assert_eq!(commands[0].unexpected_output.as_deref(), Some("ERROR"));
+        assert_eq!(commands[0].expected_output, None);
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn output_not_contains_fails_with_the_text() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:output-not-contains
+            $ echo 'ERROR: disk full'
+            ERROR
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 2, "{output}");
+        assert!(
+            output.contains("Unexpected 'ERROR' found in output"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn compare_stderr_checks_the_standard_error_only() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:compare-stderr
+            $ { echo to stdout; echo to stderr >&2; }
+            to stderr
+            ```
+        "};
+        let commands = commands(markdown);
+        assert_eq!(commands[0].expected_stderr.as_deref(), Some("to stderr"));
+        assert_eq!(commands[0].expected_output, None);
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+
+        let failing = markdown.replace("to stderr\n```", "to stdout\n```");
+        let (code, output) = run(&script(&failing, &ScriptOptions::default()));
+        assert_eq!(code, 2, "{output}");
+    }
+
+    #[test]
+    fn compare_stderr_composes_with_exit_code() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:compare-stderr,bashtestmd:exit-code=2
+            $ { echo 'error: unexpected argument' >&2; exit 2; }
+            error: unexpected argument
+            ```
+        "};
+        let commands = commands(markdown);
+        assert_eq!(commands[0].exit_code, Some(ExitCode::Exactly(2)));
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+
+        let failing = markdown.replace("exit-code=2", "exit-code=3");
+        let (code, output) = run(&script(&failing, &ScriptOptions::default()));
+        assert_eq!(code, 1, "{output}");
+    }
+
+    #[test]
+    fn background_delay_overrides_the_sleep_of_long_running_commands() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:long-running,bashtestmd:background-delay=7
+            $ sleep 1000
+            ```
+        "};
+        assert_eq!(commands(markdown)[0].background_delay, Some(7));
+        let script = script(markdown, &ScriptOptions::default());
+        assert!(script.contains("\nsleep 7\n"), "{script}");
+        assert!(!script.contains("sleep 120"), "{script}");
+    }
+
+    #[test]
+    fn long_running_commands_sleep_for_the_default_background_delay() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:long-running
+            $ sleep 1000
+            ```
+        "};
+        let default = script(markdown, &ScriptOptions::default());
+        assert!(default.contains("\nsleep 120\n"), "{default}");
+        let options = ScriptOptions {
+            default_background_delay: 5,
+            ..Default::default()
+        };
+        let configured = script(markdown, &options);
+        assert!(configured.contains("\nsleep 5\n"), "{configured}");
+    }
+
+    #[test]
+    fn malformed_exit_codes_are_errors() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:exit-code=zero
+            $ true
+            ```
+        "};
+        assert_eq!(convert(markdown).err().unwrap().errors, 1);
+    }
+
+    #[test]
+    fn malformed_wait_until_tags_are_errors() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:long-running,bashtestmd:wait-until=
+            $ sleep 1000
+            ```
+
+            ```sh,test-ci,bashtestmd:long-running,bashtestmd:wait-until=Ready,bashtestmd:wait-until-timeout=soon
+            $ sleep 1000
+            ```
+        "};
+        assert_eq!(convert(markdown).err().unwrap().errors, 2);
+    }
+
+    #[test]
+    fn tags_match_exactly() {
+        let markdown = indoc! {"
+            ```rust,test-ci
+            $ echo test-ci
+            ```
+
+            ```sh,fastest
+            $ echo fastest
+            ```
+
+            ```bash,test
+            $ echo test
+            ```
+        "};
+        let ast = markdown::to_mdast(markdown, &markdown::ParseOptions::gfm()).unwrap();
+        let selected: Vec<String> = get_all_code_blocks(ast)
+            .into_iter()
+            .filter(|code_block| is_selected(code_block, "test"))
+            .map(|code_block| code_block.value)
+            .collect();
+        assert_eq!(selected, ["$ echo test"]);
+    }
+
+    #[test]
+    fn env_sets_a_variable_for_the_block() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:env=GREETING=hello,bashtestmd:compare-output
+            $ echo "$GREETING"
+            hello
+            ```
+
+            ```sh,test-ci,bashtestmd:compare-output
+            $ echo "[${GREETING:-unset}]"
+            [unset]
+            ```
+        "#};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn env_can_be_repeated() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:env=A=1,bashtestmd:env=B=2,bashtestmd:compare-output
+            $ echo "$A $B"
+            1 2
+            ```
+        "#};
+        assert_eq!(commands(markdown)[0].env.len(), 2);
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn env_values_can_have_spaces_and_equal_signs() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:env="OPTS=--level=debug --color",bashtestmd:compare-output
+            $ echo "$OPTS"
+            --level=debug --color
+            ```
+        "#};
+        assert_eq!(
+            commands(markdown)[0].env,
+            [("OPTS".to_string(), "--level=debug --color".to_string())]
+        );
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn stdin_feeds_the_lines_of_the_block_to_the_command() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:stdin
+            $ cat
+            first line
+            $ not a command
+            last line
+            ```
+        "};
+        let commands = commands(markdown);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(
+            commands[0].stdin.as_deref(),
+            Some("first line\n$ not a command\nlast line\n")
+        );
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+        assert!(!output.contains("Running: 'not a command'"), "{output}");
+    }
+
+    #[test]
+    fn stdin_reaches_the_command_unchanged() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:stdin
+            $ diff - <(printf '%s\\n' 'first line' '$ not a command')
+            first line
+            $ not a command
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn stdin_and_compare_output_are_exclusive() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:stdin,bashtestmd:compare-output
+            $ cat
+            hello
+            ```
+        "};
+        assert_eq!(convert(markdown).err().unwrap().errors, 1);
+    }
+
+    #[test]
+    fn retries_check_the_output_of_every_attempt() {
+        let counter =
+            std::env::temp_dir().join(format!("bashtestmd-retry-output-{}", std::process::id()));
+        let markdown = format!(
+            indoc! {r#"
+                ```sh,test-ci,bashtestmd:retry=3,bashtestmd:retry-delay=0,bashtestmd:compare-output
+                $ echo attempt >> {0}; wc -l < {0}
+                2
+                ```
+            "#},
+            counter.display()
+        );
+        let (code, output) = run(&script(&markdown, &ScriptOptions::default()));
+        std::fs::remove_file(&counter).unwrap();
+        assert_eq!(code, 0, "{output}");
+        assert!(output.contains("Attempt 1 of 3 failed"), "{output}");
+        assert!(!output.contains("Attempt 2 of 3 failed"), "{output}");
+    }
+
+    #[test]
+    fn retries_fail_if_every_attempt_fails() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:retry=2,bashtestmd:retry-delay=0
+            $ false
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 1, "{output}");
+        assert!(output.contains("Attempt 1 of 2 failed"), "{output}");
+    }
+
+    #[test]
+    fn commands_without_retry_run_once() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ false
+            ```
+        "};
+        let script = script(markdown, &ScriptOptions::default());
+        assert!(!script.contains("bashtestmd_attempt"), "{script}");
+        assert_eq!(run(&script).0, 1);
+    }
+
+    #[test]
+    fn timeout_wraps_the_command() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:timeout=1
+            $ sleep 5
+            ```
+        "};
+        let script = script(markdown, &ScriptOptions::default());
+        assert!(script.contains("timeout 1 "), "{script}");
+        let (code, output) = run(&script);
+        assert_eq!(code, 3, "{output}");
+    }
+
+    #[test]
+    fn timeouts_fail_whatever_the_expected_exit_code() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:timeout=1,bashtestmd:exit-code=124,bashtestmd:compare-output
+            $ sleep 5; echo late
+            late
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 3, "{output}");
+    }
+
+    #[test]
+    fn commands_have_no_timeout_by_default() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ sleep 1
+            ```
+        "};
+        let script = script(markdown, &ScriptOptions::default());
+        assert!(!script.contains("timeout "), "{script}");
+    }
+
+    #[test]
+    fn teardown_commands_are_in_the_exit_trap() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ echo main
+            ```
+
+            ```sh,test-ci,bashtestmd:teardown
+            $ echo cleanup
+            ```
+        "};
+        let script = script(markdown, &ScriptOptions::default());
+        let body_start = script.find("bashtestmd_teardown() {").unwrap();
+        let body_end = body_start + script[body_start..].find("\n}\n").unwrap();
+        assert!(
+            script[body_start..body_end].contains("echo cleanup"),
+            "{script}"
+        );
+        assert!(
+            !script[body_start..body_end].contains("echo main"),
+            "{script}"
+        );
+        assert!(script.contains("bashtestmd_teardown; "), "{script}");
+    }
+
+    #[test]
+    fn teardown_commands_run_after_failures() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ false
+            ```
+
+            ```sh,test-ci,bashtestmd:teardown
+            $ echo cleanup
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 1, "{output}");
+        assert!(output.contains("Running: 'echo cleanup'"), "{output}");
+    }
+
+    #[test]
+    fn setup_commands_run_first() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ echo main
+            ```
+
+            ```sh,test-ci,bashtestmd:setup
+            $ echo setup
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+        let setup = output.find("Running: 'echo setup'").unwrap();
+        let main = output.find("Running: 'echo main'").unwrap();
+        assert!(setup < main, "{output}");
+    }
+
+    #[test]
+    fn commands_json_describes_every_command() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:compare-output
+            $ echo "hello"
+            "hello"
+            ```
+
+            ```sh,test-ci,bashtestmd:long-running,bashtestmd:wait-until=Listening
+            $ my-server
+            ```
+        "#};
+        assert_eq!(
+            commands_json(&commands(markdown)),
+            concat!(
+                r#"[{"cmd":"echo \"hello\"","line":2,"block":0,"long_running":false,"exit_code":0,"expected_output":"\"hello\"\u000a","wait_until":null},"#,
+                r#"{"cmd":"my-server","line":7,"block":1,"long_running":true,"exit_code":0,"expected_output":null,"wait_until":"Listening"}]"#
+            )
+        );
+    }
+
+    #[test]
+    fn keep_going_runs_every_command_and_exits_with_the_first_failure() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:compare-output
+            $ echo hello
+            goodbye
+            ```
+
+            ```sh,test-ci
+            $ false
+            ```
+
+            ```sh,test-ci
+            $ echo last
+            ```
+        "};
+        let options = ScriptOptions {
+            keep_going: true,
+            ..Default::default()
+        };
+        let (code, output) = run(&script(markdown, &options));
+        assert_eq!(code, 2, "{output}");
+        assert!(output.contains("Running: 'echo last'"), "{output}");
+        assert!(output.contains("2 of 3 commands failed"), "{output}");
+    }
+
+    #[test]
+    fn scripts_stop_at_the_first_failure_without_keep_going() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ false
+            ```
+
+            ```sh,test-ci
+            $ echo last
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 1, "{output}");
+        assert!(!output.contains("Running: 'echo last'"), "{output}");
+    }
+
+    #[test]
+    fn skipped_blocks_have_no_commands() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ echo before
+            ```
+
+            ```sh,test-ci,bashtestmd:skip,bashtestmd:long-running,bashtestmd:wait-until=Ready
+            $ echo skipped
+            ```
+
+            ```sh,test-ci
+            $ echo after
+            ```
+        "};
+        let cmds: Vec<String> = commands(markdown).into_iter().map(|cmd| cmd.cmd).collect();
+        assert_eq!(cmds, ["echo before", "echo after"]);
+    }
+
+    #[test]
+    fn the_shebang_uses_the_shell() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ echo hello
+            ```
+        "};
+        let default = script(markdown, &ScriptOptions::default());
+        assert!(default.starts_with("#!/usr/bin/env bash\n"), "{default}");
+        let options = ScriptOptions {
+            shell: "/bin/bash".to_string(),
+            ..Default::default()
+        };
+        let pinned = script(markdown, &options);
+        assert!(pinned.starts_with("#!/bin/bash\n"), "{pinned}");
+    }
+
+    #[test]
+    fn locations_have_the_file_and_the_line_of_the_block() {
+        let code_block = mdast::Code {
+            value: "$ echo hello".to_string(),
+            position: Some(markdown::unist::Position::new(142, 1, 0, 144, 4, 0)),
+            lang: Some("sh,test-ci".to_string()),
+            meta: None,
+        };
+        assert_eq!(location("README.md", &code_block), "README.md:142: ");
+        assert_eq!(location("", &code_block), "line 142: ");
+    }
+
+    #[test]
+    fn captured_outputs_are_variables_of_later_commands() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:capture=ID
+            $ echo 42
+            ```
+
+            ```sh,test-ci,bashtestmd:compare-output
+            $ echo "id: $ID"
+            id: 42
+            ```
+        "#};
+        let commands = commands(markdown);
+        assert_eq!(commands[0].capture.as_deref(), Some("ID"));
+        let script = script(markdown, &ScriptOptions::default());
+        assert!(script.contains("ID=$output"), "{script}");
+        let (code, output) = run(&script);
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn capture_needs_a_valid_variable_name() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:capture=MY-ID
+            $ echo 42
+            ```
+        "};
+        assert_eq!(convert(markdown).err().unwrap().errors, 1);
+    }
+
+    #[test]
+    fn strip_ansi_compares_the_plain_text_of_the_output() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:compare-output,bashtestmd:strip-ansi
+            $ printf '\e[1;32mStatus:\e[0m OK\n'
+            Status: OK
+            ```
+        "#};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+
+        let colored = markdown.replace(",bashtestmd:strip-ansi", "");
+        let (code, output) = run(&script(&colored, &ScriptOptions::default()));
+        assert_eq!(code, 2, "{output}");
+    }
+
+    #[test]
+    fn output_matches_passes_if_a_line_matches() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:output-matches="^block height: [0-9]+$"
+            $ printf 'syncing\nblock height: 1234\n'
+            block height: whatever
+            ```
+        "#};
+        assert_eq!(
+            commands(markdown)[0].output_matches,
+            ["^block height: [0-9]+$"]
+        );
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn output_matches_fails_if_no_line_matches() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:output-matches="^[0-9a-f]{40}$"
+            $ echo 'hash: not-a-hash'
+            ```
+        "#};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 2, "{output}");
+        assert!(output.contains("^[0-9a-f]{40}$"), "{output}");
+    }
+
+    #[test]
+    fn exit_code_nonzero_passes_on_any_failure() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:exit-code-nonzero
+            $ (exit 42)
+            ```
+        "};
+        assert_eq!(commands(markdown)[0].exit_code, Some(ExitCode::NonZero));
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+
+        let passing = markdown.replace("(exit 42)", "true");
+        let (code, output) = run(&script(&passing, &ScriptOptions::default()));
+        assert_eq!(code, 1, "{output}");
+    }
+
+    #[test]
+    fn exit_code_ranges_pass_inside_the_range() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:exit-code=1-125
+            $ (exit 125)
+            ```
+        "};
+        assert_eq!(
+            commands(markdown)[0].exit_code,
+            Some(ExitCode::Range(1, 125))
+        );
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn exit_code_ranges_fail_outside_the_range() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:exit-code=1-125
+            $ (exit 126)
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 1, "{output}");
+    }
+
+    #[test]
+    fn expected_output_files_are_compared_with_the_output() {
+        let fixture =
+            std::env::temp_dir().join(format!("bashtestmd-expected-{}.txt", std::process::id()));
+        std::fs::write(&fixture, "line 1\nline 2\n").unwrap();
+        let markdown = format!(
+            indoc! {"
+                ```sh,test-ci,bashtestmd:expected-output-file={}
+                $ printf 'line 1\\nline 2\\n'
+                ```
+            "},
+            fixture.display()
+        );
+        let passing = script(&markdown, &ScriptOptions::default());
+        let failing = script(
+            &markdown.replace("line 2", "line 3"),
+            &ScriptOptions::default(),
+        );
+        let (passing_code, passing_output) = run(&passing);
+        let (failing_code, failing_output) = run(&failing);
+        std::fs::remove_file(&fixture).unwrap();
+        assert_eq!(passing_code, 0, "{passing_output}");
+        assert_eq!(failing_code, 2, "{failing_output}");
+    }
+
+    #[test]
+    fn missing_expected_output_files_fail_the_script() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:expected-output-file=/no/such/file.txt
+            $ echo hello
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 2, "{output}");
+        assert!(
+            output.contains("The file with the expected output does not exist"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn expected_output_files_and_compare_output_are_exclusive() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:expected-output-file=expected.txt,bashtestmd:compare-output
+            $ echo hello
+            hello
+            ```
+        "};
+        assert_eq!(convert(markdown).err().unwrap().errors, 1);
+    }
+
+    #[test]
+    fn blocks_in_shell_languages_are_selected_without_a_tag() {
+        let markdown = indoc! {"
+            ```bash
+            $ echo bash
+            ```
+
+            ```sh,test-ci
+            $ echo sh
+            ```
+
+            ```shell
+            $ echo shell
+            ```
+
+            ```rust
+            fn main() {}
+            ```
+
+            ```
+            $ echo no language
+            ```
+        "};
+        let ast = markdown::to_mdast(markdown, &markdown::ParseOptions::gfm()).unwrap();
+        let code_blocks = get_all_code_blocks(ast);
+        let selected: Vec<&str> = code_blocks
+            .iter()
+            .filter(|code_block| is_selected(code_block, ""))
+            .map(|code_block| code_block.value.as_str())
+            .collect();
+        assert_eq!(selected, ["$ echo bash", "$ echo sh", "$ echo shell"]);
+
+        let conversion =
+            convert_code_blocks_into_commands(code_blocks, &ConversionOptions::default()).unwrap();
+        assert_eq!(conversion.commands.len(), 3);
+        assert_eq!(conversion.warnings, 0);
+    }
+
+    #[test]
+    fn crlf_line_endings_are_like_lf() {
+        let markdown =
+            "```sh,test-ci,bashtestmd:compare-output\r\n$ echo hello\r\nhello\r\n```\r\n";
+        let commands = commands(markdown);
+        assert_eq!(commands[0].cmd, "echo hello");
+        assert_eq!(commands[0].expected_output.as_deref(), Some("hello\n"));
+        let script = script(markdown, &ScriptOptions::default());
+        assert!(!script.contains('\r'), "{script}");
+        let (code, output) = run(&script);
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn carriage_returns_in_code_blocks_are_dropped() {
+        let code_block = mdast::Code {
+            value: "$ echo hello \\\r\n    world\r\nhello world\r\n".to_string(),
+            position: None,
+            lang: Some("sh,test-ci,bashtestmd:compare-output".to_string()),
+            meta: None,
+        };
+        let options = ConversionOptions {
+            tag: "test-ci".to_string(),
+            quiet: true,
+            ..Default::default()
+        };
+        let commands = convert_code_blocks_into_commands(vec![code_block], &options)
+            .unwrap()
+            .commands;
+        assert_eq!(commands[0].cmd, "echo hello \\\n    world");
+        assert_eq!(
+            commands[0].expected_output.as_deref(),
+            Some("hello world\n")
+        );
+    }
+
+    /// Runs a block printing `printed` and expecting `expected`, with the
+    /// comparison tags `tags`, returning the exit code of the script.
+    fn compare(tags: &str, printed: &str, expected: &str) -> i32 {
+        let markdown = format!("```sh,test-ci,{tags}\n$ printf '{printed}\\n'\n{expected}\n```\n");
+        run(&script(&markdown, &ScriptOptions::default())).0
+    }
+
+    #[test]
+    fn compare_output_ci_ignores_the_case() {
+        let tags = "bashtestmd:compare-output,bashtestmd:compare-output-ci";
+        assert_eq!(compare(tags, "Status: OK", "status: ok"), 0);
+        assert_eq!(compare(tags, "Status:  OK", "status: ok"), 2);
+        assert_eq!(
+            compare("bashtestmd:compare-output", "Status: OK", "status: ok"),
+            2
+        );
+    }
+
+    #[test]
+    fn compare_output_trim_collapses_whitespace() {
+        let tags = "bashtestmd:compare-output,bashtestmd:compare-output-trim";
+        assert_eq!(compare(tags, "status:   ok", "  status: ok  "), 0);
+        assert_eq!(compare(tags, "Status:   OK", "status: ok"), 2);
+        assert_eq!(
+            compare("bashtestmd:compare-output", "status:   ok", "status: ok"),
+            2
+        );
+    }
+
+    #[test]
+    fn compare_output_ci_and_trim_combine() {
+        let tags = "bashtestmd:compare-output-ci,bashtestmd:compare-output-trim";
+        assert_eq!(compare(tags, "Status:   OK", "status: ok"), 0);
+        assert_eq!(compare(tags, "Status:   FAILED", "status: ok"), 2);
+    }
+
+    #[test]
+    fn directives_tag_the_next_code_block() {
+        let markdown = indoc! {r#"
+            <!-- bashtestmd: exit-code=1, long-running, wait-until="Server started, listening" -->
+            ```sh,test-ci
+            $ echo "Server started, listening"
+            ```
+
+            ```sh,test-ci
+            $ echo next
+            ```
+        "#};
+        let commands = commands(markdown);
+        assert_eq!(commands[0].exit_code, Some(ExitCode::Exactly(1)));
+        assert!(commands[0].long_running);
+        assert_eq!(
+            commands[0].wait_until.as_deref(),
+            Some("Server started, listening")
+        );
+        assert_eq!(commands[1].exit_code, Some(ExitCode::Exactly(0)));
+        assert!(!commands[1].long_running);
+    }
+
+    #[test]
+    fn directives_only_apply_right_before_a_code_block() {
+        let markdown = indoc! {"
+            <!-- bashtestmd: exit-code=1 -->
+
+            Some text.
+
+            ```sh,test-ci
+            $ true
+            ```
+        "};
+        assert_eq!(commands(markdown)[0].exit_code, Some(ExitCode::Exactly(0)));
+    }
+
+    #[test]
+    fn tap_prints_a_test_point_per_command() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ echo hello
+            ```
+
+            ```sh,test-ci,bashtestmd:compare-output
+            $ echo hello
+            goodbye
+            ```
+        "};
+        let options = ScriptOptions {
+            tap: true,
+            keep_going: true,
+            ..Default::default()
+        };
+        let (code, output) = run(&script(markdown, &options));
+        assert_eq!(code, 2, "{output}");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "1..2", "{output}");
+        assert!(lines.contains(&"ok 1 - echo hello"), "{output}");
+        assert!(lines.contains(&"not ok 2 - echo hello"), "{output}");
+        assert!(lines.contains(&"# Running: 'echo hello'"), "{output}");
+        assert!(lines.contains(&"# 1 of 2 commands failed"), "{output}");
+    }
+
+    #[test]
+    fn parallel_groups_run_in_the_background_and_are_joined() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:parallel-group=services
+            $ sleep 2; echo first
+            ```
+
+            ```sh,test-ci,bashtestmd:parallel-group=services,bashtestmd:compare-output
+            $ sleep 2; echo second
+            second
+            ```
+
+            ```sh,test-ci
+            $ echo after
+            ```
+        "};
+        let script = script(markdown, &ScriptOptions::default());
+        assert!(
+            script.contains("bashtestmd_parallel_pids[0]=$!"),
+            "{script}"
+        );
+        assert!(
+            script.contains("bashtestmd_parallel_pids[1]=$!"),
+            "{script}"
+        );
+        assert!(
+            script.contains("wait ${bashtestmd_parallel_pids[1]}"),
+            "{script}"
+        );
+        let started = std::time::Instant::now();
+        let (code, output) = run(&script);
+        assert_eq!(code, 0, "{output}");
+        // Less than the 4 seconds of running the blocks one after the other.
+        assert!(started.elapsed() < std::time::Duration::from_millis(3500));
+        let first = output.find("first").unwrap();
+        let second = output.find("second").unwrap();
+        let after = output.find("Running: 'echo after'").unwrap();
+        assert!(first < second && second < after, "{output}");
+    }
+
+    #[test]
+    fn parallel_groups_fail_if_a_block_fails() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:parallel-group=services
+            $ true
+            ```
+
+            ```sh,test-ci,bashtestmd:parallel-group=services,bashtestmd:compare-output
+            $ echo hello
+            goodbye
+            ```
+
+            ```sh,test-ci
+            $ echo after
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 2, "{output}");
+        assert!(!output.contains("Running: 'echo after'"), "{output}");
+    }
+
+    #[test]
+    fn running_banners_show_the_commands_as_they_are() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci
+            $ echo 'it'\''s' > /dev/null
+            $ echo "double $HOME" > /dev/null
+            $ GREETING=hi; echo "$GREETING" `true` > /dev/null
+            ```
+        "#};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+        let banners: Vec<&str> = output
+            .lines()
+            .filter(|line| line.starts_with("Running: "))
+            .collect();
+        assert_eq!(
+            banners,
+            [
+                r#"Running: 'echo 'it'\''s' > /dev/null'"#,
+                r#"Running: 'echo "double $HOME" > /dev/null'"#,
+                r#"Running: 'GREETING=hi; echo "$GREETING" `true` > /dev/null'"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn wait_until_matches_literally() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:long-running,bashtestmd:wait-until=v1.2.3,bashtestmd:wait-until-interval=1,bashtestmd:wait-until-timeout=1
+            $ { echo 'Running v1.2.3'; exec sleep 10; }
+            ```
+        "};
+        let script = script(markdown, &ScriptOptions::default());
+        assert!(script.contains("until grep -q -i -F v1.2.3 "), "{script}");
+        let (code, output) = run(&script);
+        assert_eq!(code, 0, "{output}");
+
+        let wildcard = markdown.replace("'Running v1.2.3'", "'Running v1x2x3'");
+        let (code, output) = run(&super::tests::script(&wildcard, &ScriptOptions::default()));
+        assert_eq!(code, 3, "{output}");
+    }
+
+    #[test]
+    fn wait_until_regex_matches_patterns() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:long-running,bashtestmd:wait-until="height: [0-9]+",bashtestmd:wait-until-regex,bashtestmd:wait-until-timeout=2
+            $ { echo 'block height: 1234'; exec sleep 10; }
+            ```
+        "#};
+        let script = script(markdown, &ScriptOptions::default());
+        assert!(script.contains("until grep -q -i -E "), "{script}");
+        let (code, output) = run(&script);
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn script_blocks_run_as_a_whole() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:script
+            for word in hello world; do
+              echo "$word"
+            done
+            ```
+        "#};
+        let commands = commands(markdown);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(
+            commands[0].cmd,
+            "for word in hello world; do\n  echo \"$word\"\ndone"
+        );
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+        assert!(output.contains("hello\nworld"), "{output}");
+    }
+
+    #[test]
+    fn script_blocks_check_the_exit_code() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:script,bashtestmd:exit-code=3
+            echo starting
+            (exit 3)
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+
+        let unexpected = markdown.replace("exit-code=3", "exit-code=0");
+        let (code, output) = run(&script(&unexpected, &ScriptOptions::default()));
+        assert_eq!(code, 1, "{output}");
+    }
+
+    #[test]
+    fn daemons_check_their_process_after_the_grace_period() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:daemon,bashtestmd:background-delay=1
+            $ exec sleep 10
+            ```
+
+            ```sh,test-ci
+            $ echo after
+            ```
+        "};
+        let commands = commands(markdown);
+        assert!(commands[0].daemon);
+        let script = script(markdown, &ScriptOptions::default());
+        assert!(
+            script.contains("background_process_pid=$!\nsleep 1\n"),
+            "{script}"
+        );
+        assert!(
+            script.contains("if ! ps $background_process_pid > /dev/null; then"),
+            "{script}"
+        );
+        assert!(!script.contains("sleep 120"), "{script}");
+        let (code, output) = run(&script);
+        assert_eq!(code, 0, "{output}");
+        assert!(output.contains("Running: 'echo after'"), "{output}");
+    }
+
+    #[test]
+    fn daemons_that_exit_right_away_fail() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:daemon,bashtestmd:background-delay=1
+            $ echo crashed
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 4, "{output}");
+        assert!(output.contains("crashed"), "{output}");
+    }
+
+    #[test]
+    fn every_conflicting_pair_is_reported() {
+        for (a, b, _) in CONFLICTING_TAGS {
+            let code_block = mdast::Code {
+                value: "$ true".to_string(),
+                position: None,
+                lang: Some(format!("sh,test-ci,bashtestmd:{a}=1,bashtestmd:{b}=1")),
+                meta: None,
+            };
+            let conflicts = tag_conflicts(&code_block);
+            assert_eq!(conflicts.len(), 1, "{a} and {b}: {conflicts:?}");
+            assert!(conflicts[0].contains(&format!("`bashtestmd:{a}`")));
+            assert!(conflicts[0].contains(&format!("`bashtestmd:{b}`")));
+        }
+    }
+
+    #[test]
+    fn conflicting_tags_are_errors() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:long-running,bashtestmd:compare-output
+            $ echo hello
+            hello
+            ```
+
+            ```sh,test-ci,bashtestmd:stdin,bashtestmd:script
+            cat
+            ```
+        "};
+        assert_eq!(convert(markdown).err().unwrap().errors, 2);
+    }
+
+    #[test]
+    fn duplicate_numeric_tags_are_errors_unless_equal() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:timeout=1,bashtestmd:timeout=2
+            $ true
+            ```
+        "};
+        assert_eq!(convert(markdown).err().unwrap().errors, 1);
+
+        let same = markdown.replace("timeout=2", "timeout=1");
+        assert_eq!(commands(&same)[0].timeout, Some(1));
+    }
+
+    #[test]
+    fn trailing_blank_lines_of_the_expected_output_dont_count() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:compare-output
+            $ printf 'hello   \\n'
+            hello
+
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn trailing_blank_lines_of_the_output_dont_count() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:compare-output
+            $ printf 'hello\\n\\n\\n'
+            hello   
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn compare_output_exact_counts_trailing_whitespace() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:compare-output-exact
+            $ printf 'hello   \\n'
+            hello
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 2, "{output}");
+    }
+
+    #[test]
+    fn dump_long_running_output_prints_the_output_once_ready() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:long-running,bashtestmd:wait-until=Ready
+            $ { echo Ready; echo details; exec sleep 10; }
+            ```
+        "};
+        let options = ScriptOptions {
+            dump_long_running_output: true,
+            ..Default::default()
+        };
+        let dumping = script(markdown, &options);
+        assert!(
+            dumping.contains("\n\ncheck_and_output_long_running_output\n"),
+            "{dumping}"
+        );
+        let (code, output) = run(&dumping);
+        assert_eq!(code, 0, "{output}");
+        assert!(
+            output.contains("Output of the long running task:"),
+            "{output}"
+        );
+
+        let quiet = script(markdown, &ScriptOptions::default());
+        assert!(
+            !quiet.contains("\n\ncheck_and_output_long_running_output\n"),
+            "{quiet}"
+        );
+        let (code, output) = run(&quiet);
+        assert_eq!(code, 0, "{output}");
+        assert!(
+            !output.contains("Output of the long running task:"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn commands_listing_describes_every_command_and_its_checks() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:compare-output
+            $ echo hello
+            hello
+            ```
+
+            ```sh,test-ci,bashtestmd:long-running,bashtestmd:wait-until=Listening
+            $ my-server
+            ```
+        "};
+        assert_eq!(
+            commands_listing(&commands(markdown)),
+            indoc! {"
+                1. echo hello (line 2)
+                   exit code: 0
+                   expected output:
+                     hello
+                2. my-server (line 7)
+                   long running, waits until the output contains 'Listening'
+            "}
+        );
+    }
+
+    #[test]
+    fn heredocs_are_part_of_the_command() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:compare-output
+            $ cat <<'EOF' | tr a-z A-Z
+            hello $USER
+            $ not a command
+            EOF
+            HELLO $USER
+            ```
+        "#};
+        let commands = commands(markdown);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(
+            commands[0].cmd,
+            "cat <<'EOF' | tr a-z A-Z\nhello $USER\n$ not a command\nEOF"
+        );
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn commands_can_follow_heredocs() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ cat > /dev/null <<EOF
+            text
+            EOF
+            $ echo next
+            ```
+        "};
+        let cmds: Vec<String> = commands(markdown).into_iter().map(|cmd| cmd.cmd).collect();
+        assert_eq!(cmds, ["cat > /dev/null <<EOF\ntext\nEOF", "echo next"]);
+    }
+
+    #[test]
+    fn heredocs_with_a_dash_end_at_an_indented_terminator() {
+        let markdown = "```sh,test-ci,bashtestmd:compare-output\n$ cat <<-\"END\"\n\tindented\n\tEND\nindented\n```\n";
+        let commands = commands(markdown);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].cmd, "cat <<-\"END\"\n\tindented\n\tEND");
+        assert_eq!(commands[0].expected_output.as_deref(), Some("indented\n"));
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn working_dir_is_escaped_in_the_cd_header() {
+        let dir = std::env::temp_dir().join(format!("bashtestmd work {}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ pwd
+            ```
+        "};
+        let options = ScriptOptions {
+            working_dir: Some(dir.display().to_string()),
+            ..Default::default()
+        };
+        let script = script(markdown, &options);
+        assert!(
+            script.contains(&format!("if ! cd '{}'; then", dir.display())),
+            "{script}"
+        );
+        let (code, output) = run(&script);
+        std::fs::remove_dir(&dir).unwrap();
+        assert_eq!(code, 0, "{output}");
+        assert!(
+            output.lines().any(|line| Path::new(line) == dir),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn missing_working_dirs_fail_the_script() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ echo hello
+            ```
+        "};
+        let options = ScriptOptions {
+            working_dir: Some("/no/such dir".to_string()),
+            ..Default::default()
+        };
+        let (code, output) = run(&script(markdown, &options));
+        assert_eq!(code, 1, "{output}");
+        assert!(!output.contains("Running: 'echo hello'"), "{output}");
+    }
+
+    #[test]
+    fn dedenting_keeps_the_indentation_inside_heredocs() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:compare-output
+              $ cat <<EOF
+                  indented
+              EOF
+                  indented
+            ```
+        "};
+        let commands = commands(markdown);
+        assert_eq!(commands[0].cmd, "cat <<EOF\n    indented\nEOF");
+        assert_eq!(
+            commands[0].expected_output.as_deref(),
+            Some("    indented\n")
+        );
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn max_runtime_terminates_the_script() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ sleep 10
+            ```
+
+            ```sh,test-ci
+            $ echo after
+            ```
+        "};
+        let options = ScriptOptions {
+            max_runtime: Some(1),
+            ..Default::default()
+        };
+        let script = script(markdown, &options);
+        assert!(
+            script.contains("# Terminates the script after 1s"),
+            "{script}"
+        );
+        let started = std::time::Instant::now();
+        let (code, output) = run(&script);
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(code, 3, "{output}");
+        assert!(
+            output.contains("Overall timeout of 1s exceeded, terminating"),
+            "{output}"
+        );
+        assert!(!output.contains("Running: 'echo after'"), "{output}");
+        assert!(!output.contains("No such process"), "{output}");
+    }
+
+    #[test]
+    fn scripts_have_no_watchdog_by_default() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ echo hello
+            ```
+        "};
+        let script = script(markdown, &ScriptOptions::default());
+        assert!(!script.contains("bashtestmd_kill_tree"), "{script}");
+    }
+
+    #[test]
+    fn ignored_exit_codes_dont_stop_the_script() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:exit-code-ignore
+            $ false
+            ```
+
+            ```sh,test-ci
+            $ echo after
+            ```
+        "};
+        assert_eq!(commands(markdown)[0].exit_code, None);
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+        assert!(output.contains("Running: 'echo after'"), "{output}");
+    }
+
+    #[test]
+    fn success_messages_can_be_changed_or_left_out() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ true
+            ```
+        "};
+        let (code, output) = run(&script(markdown, &ScriptOptions::default()));
+        assert_eq!(code, 0, "{output}");
+        assert!(output.ends_with("All tests passed!\n"), "{output}");
+
+        let custom = ScriptOptions {
+            success_message: Some("README steps 'OK'".to_string()),
+            ..Default::default()
+        };
+        let (code, output) = run(&script(markdown, &custom));
+        assert_eq!(code, 0, "{output}");
+        assert!(output.ends_with("README steps 'OK'\n"), "{output}");
+        assert!(!output.contains("All tests passed!"), "{output}");
+
+        let suppressed = ScriptOptions {
+            success_message: None,
+            ..Default::default()
+        };
+        let (code, output) = run(&script(markdown, &suppressed));
+        assert_eq!(code, 0, "{output}");
+        assert_eq!(output, "Running: 'true'\n");
+    }
+
+    #[test]
+    fn timings_print_how_long_each_command_took() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ echo hello
+            ```
+
+            ```sh,test-ci,bashtestmd:long-running,bashtestmd:wait-until=Ready
+            $ { echo Ready; exec sleep 10; }
+            ```
+        "};
+        let options = ScriptOptions {
+            timings: true,
+            ..Default::default()
+        };
+        let script = script(markdown, &options);
+        assert_eq!(
+            script
+                .matches("bashtestmd_started=$(bashtestmd_now_ms)")
+                .count(),
+            2,
+            "{script}"
+        );
+        let (code, output) = run(&script);
+        assert_eq!(code, 0, "{output}");
+        let took: Vec<&str> = output
+            .lines()
+            .filter(|line| line.starts_with("Took "))
+            .collect();
+        assert_eq!(took.len(), 2, "{output}");
+        assert!(took[0].ends_with("s: 'echo hello'"), "{output}");
+        assert!(
+            took[1].ends_with("s: '{ echo Ready; exec sleep 10; }'"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn outputs_are_unchanged_without_timings() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ echo hello
+            ```
+        "};
+        let script = script(markdown, &ScriptOptions::default());
+        assert!(!script.contains("bashtestmd_now_ms"), "{script}");
+        let (code, output) = run(&script);
+        assert_eq!(code, 0, "{output}");
+        assert_eq!(output, "Running: 'echo hello'\nhello\nAll tests passed!\n");
+    }
+
+    #[test]
+    fn excluded_tags_drop_selected_blocks() {
+        let markdown = indoc! {"
+            ```bash,test-ci
+            $ echo runs
+            ```
+
+            ```bash,test-ci,illustrative
+            $ echo illustrative
+            ```
+
+            ```bash,test-ci,slow
+            $ echo slow
+            ```
+        "};
+        let ast = markdown::to_mdast(markdown, &markdown::ParseOptions::gfm()).unwrap();
+        let options = ConversionOptions {
+            tag: "test-ci".to_string(),
+            exclude_tags: vec!["illustrative".to_string(), "slow".to_string()],
+            quiet: true,
+            ..Default::default()
+        };
+        let commands = convert_code_blocks_into_commands(get_all_code_blocks(ast), &options)
+            .unwrap()
+            .commands;
+        let cmds: Vec<String> = commands.into_iter().map(|cmd| cmd.cmd).collect();
+        assert_eq!(cmds, ["echo runs"]);
+    }
+
+    #[test]
+    fn traces_are_truncated_by_the_first_command_of_their_block() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ echo untraced
+            ```
+
+            ```sh,test-ci,bashtestmd:trace-to=trace.log
+            $ echo one
+            $ echo two
+            ```
+        "};
+        let commands = commands(markdown);
+        assert!(!commands[0].trace_append);
+        assert!(!commands[1].trace_append);
+        assert!(commands[2].trace_append);
+        let script = script(markdown, &ScriptOptions::default());
+        assert_eq!(script.matches(r#"}>"$bashtestmd_trace_file""#).count(), 1);
+        assert_eq!(script.matches(r#"}>>"$bashtestmd_trace_file""#).count(), 1);
+    }
+
+    #[test]
+    fn pipefail_option_is_set_once_in_the_header() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:pipefail
+            $ echo tagged
+            ```
+
+            ```sh,test-ci
+            $ false | cat
+            $ echo never
+            ```
+        "};
+        let options = ScriptOptions {
+            pipefail: true,
+            ..Default::default()
+        };
+        let with_option = script(markdown, &options);
+        assert_eq!(with_option.lines().nth(1), Some("set -o pipefail"));
+        assert_eq!(with_option.matches("set -o pipefail").count(), 1);
+        assert!(!with_option.contains("set +o pipefail"));
+        let (code, output) = run(&with_option);
+        assert_eq!(code, 1, "{output}");
+        assert!(!output.contains("Running: 'echo never'"), "{output}");
+
+        let without_option = script(markdown, &ScriptOptions::default());
+        assert_ne!(without_option.lines().nth(1), Some("set -o pipefail"));
+    }
+
+    #[test]
+    fn strip_comments_keeps_the_lines_of_heredocs() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:script,bashtestmd:strip-comments
+            # Write the readme.
+            cat <<EOF > README.md
+            # Title
+            EOF
+            # Show it.
+            cat README.md
+            ```
+        "};
+        assert_eq!(
+            commands(markdown)[0].cmd,
+            "cat <<EOF > README.md\n# Title\nEOF\ncat README.md"
+        );
+    }
+
+    #[test]
+    fn strip_comments_drops_comment_lines_of_multi_line_strings() {
+        // A documented limitation, the quotes aren't followed across lines.
+        assert_eq!(strip_comments("echo 'one\n# two'"), "echo 'one");
+    }
+
+    /// Converts the code blocks of `markdown` with `tag` and `defines`.
+    fn convert_with_defines(
+        markdown: &str,
+        tag: &str,
+        defines: &[(&str, &str)],
+    ) -> Result<Conversion, ConversionError> {
+        let ast = markdown::to_mdast(markdown, &markdown::ParseOptions::gfm()).unwrap();
+        let options = ConversionOptions {
+            tag: tag.to_string(),
+            defines: defines
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            quiet: true,
+            ..Default::default()
+        };
+        convert_code_blocks_into_commands(get_all_code_blocks(ast), &options)
+    }
+
+    #[test]
+    fn placeholders_are_replaced_with_the_tag_and_the_defines() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:compare-output
+            $ echo {{TAG}} {{VERSION}}
+            test-ci {{VERSION}}
+            ```
+        "};
+        let conversion = convert_with_defines(markdown, "test-ci", &[("VERSION", "1.2")]).unwrap();
+        assert_eq!(conversion.warnings, 0);
+        assert_eq!(conversion.commands[0].cmd, "echo test-ci 1.2");
+        assert_eq!(
+            conversion.commands[0].expected_output.as_deref(),
+            Some("test-ci 1.2\n")
+        );
+    }
+
+    #[test]
+    fn tag_placeholders_are_unresolved_without_a_tag() {
+        let markdown = indoc! {"
+            ```sh
+            $ cargo run --features {{TAG}}
+            ```
+        "};
+        let conversion = convert_with_defines(markdown, "", &[]).unwrap();
+        assert_eq!(conversion.warnings, 1);
+        assert_eq!(conversion.commands[0].cmd, "cargo run --features {{TAG}}");
+    }
+
+    #[test]
+    fn expect_diff_compares_the_output_with_the_baseline() {
+        let dir = std::env::temp_dir().join(format!("bashtestmd-diff-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let baseline = dir.join("baseline.txt");
+        std::fs::write(&baseline, "hello\n").unwrap();
+        let block = |printed: &str, path: &Path| {
+            let markdown = format!(
+                "```sh,test-ci,bashtestmd:expect-diff={}\n$ echo {printed}\n```\n",
+                path.display()
+            );
+            run(&script(&markdown, &ScriptOptions::default()))
+        };
+        let same = block("hello", &baseline);
+        let different = block("world", &baseline);
+        let missing = block("hello", &dir.join("missing.txt"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(same.0, 0, "{}", same.1);
+        assert_eq!(different.0, 2, "{}", different.1);
+        assert!(different.1.contains("-hello\n+world"), "{}", different.1);
+        assert!(different.1.contains("Output differs from baseline"));
+        assert_eq!(missing.0, 2, "{}", missing.1);
+        assert!(missing.1.contains("does not exist"), "{}", missing.1);
+    }
+
+    #[test]
+    fn only_focuses_on_the_tagged_blocks_and_keeps_setup_and_teardown() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:setup
+            $ echo setup
+            ```
+
+            ```sh,test-ci
+            $ echo ignored
+            ```
+
+            ```sh,test-ci,bashtestmd:only
+            $ echo focused
+            ```
+
+            ```sh,test-ci,bashtestmd:teardown
+            $ echo teardown
+            ```
+        "};
+        let conversion = convert(markdown).unwrap();
+        assert_eq!(conversion.warnings, 1);
+        let cmds: Vec<&str> = conversion
+            .commands
+            .iter()
+            .map(|cmd| cmd.cmd.as_str())
+            .collect();
+        assert_eq!(cmds, ["echo setup", "echo focused", "echo teardown"]);
+    }
+
+    #[test]
+    fn all_blocks_run_without_only() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ echo one
+            ```
+
+            ```sh,test-ci
+            $ echo two
+            ```
+        "};
+        let conversion = convert(markdown).unwrap();
+        assert_eq!(conversion.warnings, 0);
+        assert_eq!(conversion.commands.len(), 2);
+    }
+
+    #[test]
+    fn the_script_exits_through_the_final_exit_helper() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ echo hello
+            ```
+        "};
+        let script = script(markdown, &ScriptOptions::default());
+        assert!(script.ends_with("\nbashtestmd_final_exit\n"), "{script}");
+        assert_eq!(run(&script).0, 0);
+    }
+
+    #[test]
+    fn the_final_exit_code_is_the_one_of_the_first_failure() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:compare-output
+            $ echo hello
+            world
+            ```
+
+            ```sh,test-ci
+            $ false
+            ```
+        "};
+        let options = ScriptOptions {
+            keep_going: true,
+            ..Default::default()
+        };
+        let (code, output) = run(&script(markdown, &options));
+        assert_eq!(code, 2, "{output}");
+        assert!(output.contains("2 of 2 commands failed"), "{output}");
+    }
+
+    #[test]
+    fn exit_codes_tell_the_kind_of_failure() {
+        let exit_code = |markdown: &str| run(&script(markdown, &ScriptOptions::default())).0;
+        let command_failed = "```sh,test-ci\n$ false\n```\n";
+        let output_mismatch = "```sh,test-ci,bashtestmd:compare-output\n$ echo hello\nworld\n```\n";
+        let timeout = "```sh,test-ci,bashtestmd:timeout=1\n$ sleep 5\n```\n";
+        let readiness_failed = indoc! {"
+            ```sh,test-ci,bashtestmd:long-running,bashtestmd:wait-until=Ready,bashtestmd:wait-until-interval=1
+            $ echo crashed
+            ```
+        "};
+        assert_eq!(exit_code(command_failed), 1);
+        assert_eq!(exit_code(output_mismatch), 2);
+        assert_eq!(exit_code(timeout), 3);
+        assert_eq!(exit_code(readiness_failed), 4);
+    }
+
+    #[test]
+    fn docker_runs_the_commands_in_one_container() {
+        let dir = std::env::temp_dir().join(format!("bashtestmd-docker-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("docker.log");
+        // Logs its arguments, and runs the commands of `docker exec` locally.
+        let fake_docker = format!(
+            indoc! {r#"
+                #!/usr/bin/env bash
+                echo "$@" >> {}
+                case "$1" in
+                    run) echo container-1 ;;
+                    exec) shift 5; exec "$@" ;;
+                esac
+            "#},
+            log.display()
+        );
+        let docker = dir.join("docker");
+        std::fs::write(&docker, fake_docker).unwrap();
+        std::process::Command::new("chmod")
+            .args(["+x", docker.to_str().unwrap()])
+            .status()
+            .unwrap();
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:compare-output
+            $ echo hello
+            hello
+            ```
+        "};
+        let options = ScriptOptions {
+            docker: Some("ubuntu:24.04".to_string()),
+            ..Default::default()
+        };
+        let script = script(markdown, &options);
+        let (code, output) = run(&format!("PATH={}:$PATH\n{script}", dir.display()));
+        let log = std::fs::read_to_string(&log).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(code, 0, "{output}");
+        let calls: Vec<&str> = log
+            .lines()
+            .map(|line| line.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(calls, ["run", "exec", "rm"], "{log}");
+        assert!(log.contains("ubuntu:24.04 infinity"), "{log}");
+        assert!(log.contains("container-1 bash -c"), "{log}");
+    }
+
+    #[test]
+    fn cached_commands_only_run_until_they_succeed_once() {
+        let dir = std::env::temp_dir().join(format!("bashtestmd-cache-{}", std::process::id()));
+        let counter = dir.join("runs");
+        let markdown = format!(
+            "```sh,test-ci,bashtestmd:cache-key=download\n$ echo run >> {}\n```\n",
+            counter.display()
+        );
+        let options = ScriptOptions {
+            cache_dir: dir.join("cache").display().to_string(),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = script(&markdown, &options);
+        let first = run(&script);
+        let second = run(&script);
+        let runs = std::fs::read_to_string(&counter).unwrap();
+        let marker = dir.join("cache/download").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first.0, 0, "{}", first.1);
+        assert_eq!(second.0, 0, "{}", second.1);
+        assert!(
+            second.1.contains("Skipping (cached as 'download')"),
+            "{}",
+            second.1
+        );
+        assert_eq!(runs, "run\n");
+        assert!(marker);
+    }
+
+    #[test]
+    fn failed_cached_commands_dont_create_the_marker() {
+        let dir =
+            std::env::temp_dir().join(format!("bashtestmd-cache-fail-{}", std::process::id()));
+        let markdown = "```sh,test-ci,bashtestmd:cache-key=download\n$ false\n```\n";
+        let options = ScriptOptions {
+            cache_dir: dir.display().to_string(),
+            ..Default::default()
+        };
+        let (code, output) = run(&script(markdown, &options));
+        let marker = dir.join("download").exists();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(code, 1, "{output}");
+        assert!(!marker);
+    }
+
+    #[test]
+    fn transient_errors_are_retried_with_the_retry_delay() {
+        let counter =
+            std::env::temp_dir().join(format!("bashtestmd-transient-{}", std::process::id()));
+        let markdown = format!(
+            indoc! {r#"
+                ```sh,test-ci,bashtestmd:retry-on-output=connection refused,bashtestmd:retry-delay=0
+                $ echo attempt >> {0}; [ $(wc -l < {0}) -ge 3 ] || {{ echo "Connection refused" >&2; false; }}
+                ```
+            "#},
+            counter.display()
+        );
+        let script = script(&markdown, &ScriptOptions::default());
+        assert!(script.contains("sleep 0\n"), "{script}");
+        let (code, output) = run(&script);
+        let attempts = std::fs::read_to_string(&counter).unwrap();
+        std::fs::remove_file(&counter).unwrap();
+        assert_eq!(code, 0, "{output}");
+        assert_eq!(attempts.lines().count(), 3);
+        assert!(output.contains("(attempt 2 of 3)"), "{output}");
+    }
+
+    #[test]
+    fn other_failures_arent_retried() {
+        let counter =
+            std::env::temp_dir().join(format!("bashtestmd-not-transient-{}", std::process::id()));
+        let markdown = format!(
+            indoc! {r#"
+                ```sh,test-ci,bashtestmd:retry-on-output=connection refused
+                $ echo attempt >> {0}; echo "Permission denied" >&2; false
+                ```
+            "#},
+            counter.display()
+        );
+        let (code, output) = run(&script(&markdown, &ScriptOptions::default()));
+        let attempts = std::fs::read_to_string(&counter).unwrap();
+        std::fs::remove_file(&counter).unwrap();
+        assert_eq!(code, 1, "{output}");
+        assert_eq!(attempts, "attempt\n");
+        assert!(!output.contains("retrying"), "{output}");
+    }
+
+    #[test]
+    fn matrices_expand_into_every_combination_of_values() {
+        let markdown = indoc! {r#"
+            ```sh,test-ci,bashtestmd:matrix="NETWORK:devnet,testnet",bashtestmd:matrix="MODE:fast,slow",bashtestmd:compare-output
+            $ echo {{NETWORK}} {{MODE}}
+            {{NETWORK}} {{MODE}}
+            ```
+        "#};
+        let conversion = convert(markdown).unwrap();
+        assert_eq!(conversion.warnings, 0);
+        let cmds: Vec<&str> = conversion
+            .commands
+            .iter()
+            .map(|cmd| cmd.cmd.as_str())
+            .collect();
+        assert_eq!(
+            cmds,
+            [
+                "echo devnet fast",
+                "echo devnet slow",
+                "echo testnet fast",
+                "echo testnet slow"
+            ]
+        );
+        assert_eq!(
+            conversion.commands[3].expected_output.as_deref(),
+            Some("testnet slow\n")
+        );
+        let (code, output) =
+            run(&compile_commands_into_bash(conversion.commands, &ScriptOptions::default()).0);
+        assert_eq!(code, 0, "{output}");
+    }
+
+    #[test]
+    fn keep_going_leaves_the_commands_as_they_are() {
+        let markdown = indoc! {"
+            ```sh,test-ci,bashtestmd:script
+            bashtestmd_fail now
+            ```
+        "};
+        let options = ScriptOptions {
+            keep_going: true,
+            ..Default::default()
+        };
+        let script = script(markdown, &options);
+        assert!(script.contains("\nbashtestmd_fail now\n"), "{script}");
+        assert!(
+            script.contains("bashtestmd_fail $BASHTESTMD_EXIT_COMMAND_FAILED || break 99\n"),
+            "{script}"
+        );
+    }
+}
@@ -1,300 +1,1270 @@
-use std::collections::VecDeque;
-use std::io::{self, Write};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
-use clap::Parser;
-use indoc::indoc;
+use bashtestmd::{
+    assign_baselines, commands_json, commands_listing, compile_commands_into_bash,
+    convert_code_blocks_into_commands, fence_tokens, get_all_code_blocks, is_selected, json_string,
+    read_commands_file, Command, ConversionOptions, MessagesTo, ScriptOptions, SourceMapEntry,
+    WaitProgress,
+};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, Parser, ValueEnum};
 use markdown::mdast;
+use markdown::unist::Position;
 
 #[derive(Debug, Parser)]
 struct Args {
-    /// Input Markdown file to parse
+    /// Input Markdown file to parse, `-` for stdin. Can be repeated to run
+    /// the code blocks of several files in the given order
+    #[clap(short, long, required = true)]
+    input: Vec<String>,
+    /// Path to output Bash script, `-` (the default) for stdout
     #[clap(short, long)]
-    input: String,
-    /// Path to output Bash script
+    output: Option<String>,
+    /// Only run code blocks with this tag. Can be repeated with
+    /// `--split-by-tag`. Without it, the code blocks in bash, sh or shell run
     #[clap(short, long)]
-    output: String,
-    /// Only run code blocks with this tag
-    #[clap(short, long)]
-    tag: String,
+    tag: Vec<String>,
+    /// Skip the code blocks with this tag, even if they're selected by
+    /// `--tag`. Can be repeated
+    #[clap(long)]
+    exclude_tag: Vec<String>,
+    /// Replace the placeholder `{{NAME}}` in commands and expected outputs
+    /// with `VALUE`, given as `NAME=VALUE`. Can be repeated
+    #[clap(long, value_parser = parse_define)]
+    define: Vec<(String, String)>,
+    /// Write one script per `--tag`, named after the output path and the tag
+    /// (e.g. `demo-test-ci.sh` for `--output demo.sh --tag test-ci`)
+    #[clap(long, requires_all = ["output", "tag"])]
+    split_by_tag: bool,
+    /// File with extra commands to run after the ones from the Markdown
+    /// file, one per line
+    #[clap(long)]
+    commands_file: Option<String>,
+    /// Keep the temporary files created by the script (e.g. the output of
+    /// long running commands) and print their paths when it exits
+    #[clap(long)]
+    preserve_temp: bool,
+    /// On output mismatches, only show this many lines of context around the
+    /// first difference instead of the full expected and actual output
+    #[clap(long)]
+    mismatch_context: Option<usize>,
+    /// What relative paths in tags (e.g. `expect-diff`) are relative to
+    #[clap(long, value_enum, default_value_t = PathsRelativeTo::Cwd)]
+    paths_relative_to: PathsRelativeTo,
+    /// Run all commands as a single unit in a subshell, with document-wide
+    /// setup and teardown (see `--before` and `--after`)
+    #[clap(long)]
+    atomic: bool,
+    /// Command to run before all others in `--atomic` mode
+    #[clap(long, requires = "atomic")]
+    before: Vec<String>,
+    /// Command to run after all others in `--atomic` mode, even if one of
+    /// them failed
+    #[clap(long, requires = "atomic")]
+    after: Vec<String>,
+    /// Directory with the recorded outputs of commands without output
+    /// checks. Commands fail if their output drifts from the recording
+    #[clap(long)]
+    baseline_dir: Option<String>,
+    /// Treat tagged code blocks without commands as errors
+    #[clap(long)]
+    werror: bool,
+    /// Treat all warnings about the code blocks (e.g. unknown `bashtestmd:`
+    /// tags) as errors
+    #[clap(long)]
+    strict: bool,
+    /// Run the commands in a Docker container created from this image
+    #[clap(long)]
+    docker: Option<String>,
+    /// Directory the script changes to before running the commands, instead
+    /// of running them where it's started
+    #[clap(long)]
+    working_dir: Option<String>,
+    /// Directory where the script remembers which commands with a
+    /// `bashtestmd:cache-key` already succeeded
+    #[clap(long, default_value = ".bashtestmd-cache")]
+    cache_dir: String,
+    /// Check the generated script with `bash -n` and `shellcheck` (if
+    /// installed) instead of writing it, and exit with an error on any issue
+    #[clap(long)]
+    lint: bool,
+    /// Only report the warnings and errors in the code blocks of each `--tag`,
+    /// without writing a script, and exit with an error if there are any
+    #[clap(long, conflicts_with_all = ["output", "split_by_tag", "lint", "embed_output"])]
+    check: bool,
+    /// Also write a JSON description of the commands of the script to this
+    /// file. With `--split-by-tag`, named like the scripts
+    #[clap(long, conflicts_with_all = ["lint", "check", "embed_output", "plan_json"])]
+    report: Option<String>,
+    /// Only print errors, not warnings (e.g. about unknown tags)
+    #[clap(long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Also print each code block that is converted
+    #[clap(long)]
+    verbose: bool,
+    /// Print the tags of all code blocks and how many commands each of them
+    /// has as JSON, instead of generating a script
+    #[clap(long, conflicts_with_all = ["output", "tag"])]
+    plan_json: bool,
+    /// Only run the code blocks that changed since this git revision (all of
+    /// them if git can't tell)
+    #[clap(long)]
+    since: Option<String>,
+    /// Only run the code blocks that changed since `--since`, or since `HEAD`
+    /// without it, i.e. the uncommitted changes
+    #[clap(long)]
+    only_changed: bool,
+    /// Only print the output of a command (including the `Running:` line) if
+    /// it fails
+    #[clap(long)]
+    quiet_on_success: bool,
+    /// How the script shows that it's still waiting for the text of a
+    /// `bashtestmd:wait-until` tag
+    #[clap(long, value_enum, default_value_t = WaitProgress::Dots)]
+    wait_progress: WaitProgress,
+    /// Abort the script before the next command once it ran for this many
+    /// seconds
+    #[clap(long)]
+    max_total_duration: Option<u64>,
+    /// Terminate the running command and the script once it ran for this
+    /// many seconds
+    #[clap(long)]
+    max_runtime: Option<u64>,
+    /// Pause before each command until enter is pressed, for live demos.
+    /// Only when the script runs in a terminal
+    #[clap(long)]
+    step: bool,
+    /// Shell command the script is piped through before it's written, e.g.
+    /// to add site-specific setup
+    #[clap(long)]
+    post_process: Option<String>,
+    /// Run the commands and write the output of the ones tagged with
+    /// `bashtestmd:output-follows` into the following code block of the
+    /// input file, instead of generating a script
+    #[clap(long, conflicts_with_all = ["output", "split_by_tag", "lint"])]
+    embed_output: bool,
+    /// Run every command with `bash -c`, isolated from the state of the
+    /// script and of the other commands
+    #[clap(long)]
+    isolate: bool,
+    /// Lines of code blocks starting with this are commands
+    #[clap(long, default_value = "$ ")]
+    prompt: String,
+    /// Where the script writes its own messages (e.g. `Running:` lines and
+    /// failures), as opposed to the output of the commands
+    #[clap(long, value_enum, default_value_t = MessagesTo::Stdout)]
+    messages_to: MessagesTo,
+    /// File the script also writes all of its output to, while still showing
+    /// it
+    #[clap(long)]
+    log_file: Option<String>,
+    /// Seconds the script waits after starting a long running command without
+    /// `bashtestmd:wait-until`, unless it has a `bashtestmd:background-delay`
+    #[clap(long, default_value_t = 120)]
+    default_background_delay: u64,
+    /// Carry on with the next command after a failure instead of stopping,
+    /// and report how many commands failed at the end
+    #[clap(long, conflicts_with = "atomic")]
+    keep_going: bool,
+    /// Set `set -o pipefail` in the header of the script, so that a failure in
+    /// any stage of a pipeline fails the command
+    #[clap(long)]
+    pipefail: bool,
+    /// The interpreter in the shebang line of the script, e.g. a pinned
+    /// `/bin/bash`. It must be compatible with bash
+    #[clap(long, default_value = "/usr/bin/env bash")]
+    shell: String,
+    /// Print the results in the Test Anything Protocol (TAP), with a test
+    /// point per command
+    #[clap(long, conflicts_with = "atomic")]
+    tap: bool,
+    /// Run the script after generating it, and exit with its exit code. The
+    /// script is only written to a file if `--output` is given
+    #[clap(long, conflicts_with_all = ["split_by_tag", "lint", "check", "embed_output", "plan_json"])]
+    run: bool,
+    /// Also print the output of long running commands so far once they're
+    /// ready, not only when a command fails
+    #[clap(long)]
+    dump_long_running_output: bool,
+    /// Print a numbered list of the commands and their checks, instead of
+    /// generating a script
+    #[clap(long, conflicts_with_all = ["output", "split_by_tag", "lint", "check", "embed_output", "plan_json", "run"])]
+    list: bool,
+    /// The message the script prints when all the commands passed
+    #[clap(long, default_value = "All tests passed!")]
+    success_message: String,
+    /// Don't print a message when all the commands passed, e.g. when the
+    /// script is a fragment of a larger run
+    #[clap(long, conflicts_with = "success_message")]
+    no_success_message: bool,
+    /// Print how long each command took, or a long running command took to
+    /// be ready
+    #[clap(long)]
+    timings: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum PathsRelativeTo {
+    /// The directory of the input Markdown file
+    Input,
+    /// The working directory of the generated script when it runs
+    Cwd,
+}
+
+/// The code blocks of an input file, or of a part of it between
+/// `bashtestmd:include` directives.
+struct Input {
+    path: String,
+    contents: String,
+    code_blocks: Vec<mdast::Code>,
 }
 
 fn main() {
-    let args = Args::parse();
+    let (mut args, first_contents) = parse_args();
+    if args.tag.is_empty() {
+        // Selects the blocks in a shell language, see `ConversionOptions`.
+        args.tag.push(String::new());
+    }
+
+    let mut inputs = Vec::new();
+    for (idx, path) in args.input.iter().enumerate() {
+        // The first file was already read for its `bashtestmd:config`.
+        let contents = if idx == 0 {
+            first_contents.clone()
+        } else {
+            match read_input(path) {
+                Ok(contents) => contents,
+                Err(err) => {
+                    eprintln!("Error: {err}");
+                    std::process::exit(1);
+                }
+            }
+        };
+        if let Err(err) = read_markdown(path, contents, &mut Vec::new(), &mut inputs) {
+            eprintln!("Error: {err}");
+            std::process::exit(1);
+        }
+    }
+
+    if args.plan_json {
+        let code_blocks: Vec<mdast::Code> = inputs
+            .iter()
+            .flat_map(|input| input.code_blocks.iter().cloned())
+            .collect();
+        println!("{}", plan_json(&code_blocks, &args.prompt));
+        return;
+    }
+    let unmatched_tags = unmatched_tags(&args, &inputs);
+    if args.strict && unmatched_tags > 0 {
+        eprintln!("Error: {unmatched_tags} tag(s) matching no code block, which is an error with --strict");
+        std::process::exit(1);
+    }
+    let since = args
+        .since
+        .as_deref()
+        .or(args.only_changed.then_some("HEAD"));
+    if let Some(rev) = since {
+        for input in &mut inputs {
+            match changed_lines(&input.path, rev) {
+                Ok(changed_lines) => {
+                    input.code_blocks =
+                        only_changed(std::mem::take(&mut input.code_blocks), &changed_lines)
+                }
+                Err(err) if !args.quiet => eprintln!("Warning: running all code blocks of {}, could not get the lines changed since {rev}: {err}", input.path),
+                Err(_) => {}
+            }
+        }
+    }
+    if args.check {
+        if check(&args, &inputs, unmatched_tags) != 0 {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.list {
+        if args.tag.len() > 1 {
+            Args::command()
+                .error(
+                    ErrorKind::TooManyValues,
+                    "--tag can only be given once with --list",
+                )
+                .exit();
+        }
+        print!(
+            "{}",
+            commands_listing(&prepare_commands(&args, &inputs, &args.tag[0]))
+        );
+        return;
+    }
+    if args.embed_output {
+        if args.input.len() > 1 {
+            Args::command()
+                .error(
+                    ErrorKind::TooManyValues,
+                    "--input can only be given once with --embed-output",
+                )
+                .exit();
+        }
+        if inputs.len() > 1 {
+            eprintln!("Error: --embed-output doesn't support files with bashtestmd:include");
+            std::process::exit(1);
+        }
+        let input = &inputs[0];
+        if input.path == "-" {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--embed-output needs an input file, not stdin",
+                )
+                .exit();
+        }
+        if args.tag.len() > 1 {
+            Args::command()
+                .error(
+                    ErrorKind::TooManyValues,
+                    "--tag can only be given once with --embed-output",
+                )
+                .exit();
+        }
+        match embed_output(&args, input, &args.tag[0]) {
+            Ok(Some(markdown)) => {
+                if let Err(err) = std::fs::write(&input.path, markdown) {
+                    eprintln!("Error: could not write {}: {err}", input.path);
+                    std::process::exit(1);
+                }
+            }
+            Err(err) => {
+                eprintln!("Error: {err}");
+                std::process::exit(1);
+            }
+            Ok(None) => {
+                eprintln!("The script failed, {} was not updated", input.path);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let output = args.output.as_deref().unwrap_or("-");
+    if args.run && output == "-" && args.output.is_some() {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--run can't write the script to stdout",
+            )
+            .exit();
+    }
 
-    let file_contents = std::fs::read_to_string(&args.input).unwrap();
+    if !args.split_by_tag {
+        if args.tag.len() > 1 {
+            Args::command()
+                .error(
+                    ErrorKind::TooManyValues,
+                    "--tag can only be given once, unless --split-by-tag is used",
+                )
+                .exit();
+        }
+        let (script, source_map) =
+            generate_script(&args, &inputs, &args.tag[0], args.report.as_deref());
+        let success = if args.lint {
+            lint(&args.input[0], &script, &source_map) == 0
+        } else if args.run {
+            std::process::exit(run_script(&args, script));
+        } else {
+            write_script(&args, output, script)
+        };
+        if !success {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut outputs = Vec::new();
+    let mut tags_by_output = HashMap::new();
+    for tag in &args.tag {
+        let output = split_output_path(output, tag);
+        if let Some(other_tag) = tags_by_output.insert(output.clone(), tag) {
+            Args::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    format!("tags '{other_tag}' and '{tag}' would both be written to '{output}'"),
+                )
+                .exit();
+        }
+        outputs.push((output, tag));
+    }
+    let mut success = true;
+    for (output, tag) in outputs {
+        let report = args
+            .report
+            .as_ref()
+            .map(|report| split_output_path(report, tag));
+        let (script, source_map) = generate_script(&args, &inputs, tag, report.as_deref());
+        if args.lint {
+            success &= lint(&args.input[0], &script, &source_map) == 0;
+        } else {
+            success &= write_script(&args, &output, script);
+        }
+    }
+    if !success {
+        std::process::exit(1);
+    }
+}
+
+/// Parses a `--define` of a placeholder, `NAME=VALUE`.
+fn parse_define(define: &str) -> Result<(String, String), String> {
+    match define.split_once('=') {
+        Some((name, value))
+            if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+        {
+            Ok((name.to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected NAME=VALUE, got {define}")),
+    }
+}
+
+/// Parses the command line, using the options of the `bashtestmd:config`
+/// directive of the first input file for the ones that aren't on it. Returns
+/// them with the contents of that file, which is only read once, as it may be
+/// stdin.
+fn parse_args() -> (Args, String) {
+    let cli_args: Vec<String> = std::env::args().collect();
+    // Without errors for missing arguments, which the directive may provide.
+    let matches = Args::command()
+        .ignore_errors(true)
+        .get_matches_from(&cli_args);
+    let Some(input) = matches.get_one::<String>("input") else {
+        // Without `--input`, so this prints the usage and exits.
+        return (Args::parse_from(cli_args), String::new());
+    };
+    let file_contents = match read_input(input) {
+        Ok(file_contents) => file_contents,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    let mut args = cli_args[..1].to_vec();
+    for (name, value) in document_config(&file_contents) {
+        let Some(arg) = Args::command()
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(name.as_str()) && name != "input")
+            .cloned()
+        else {
+            if !matches.get_flag("quiet") {
+                eprintln!("Unknown option in bashtestmd:config, ignoring: {name}");
+            }
+            continue;
+        };
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        args.push(format!("--{name}"));
+        args.extend(value);
+    }
+    args.extend(cli_args.into_iter().skip(1));
+    (Args::parse_from(args), file_contents)
+}
+
+/// Adds the code blocks of the Markdown file at `path` to `inputs`, with the
+/// ones of the files it includes with `<!-- bashtestmd:include PATH -->` in
+/// their place. The paths are relative to the including file. `including`
+/// holds the files that are being read, to detect cyclic includes.
+fn read_markdown(
+    path: &str,
+    contents: String,
+    including: &mut Vec<PathBuf>,
+    inputs: &mut Vec<Input>,
+) -> Result<(), String> {
     let markdown_parse_options = markdown::ParseOptions::gfm();
-    let markdown_ast = markdown::to_mdast(&file_contents, &markdown_parse_options).unwrap();
+    let markdown_ast = markdown::to_mdast(&contents, &markdown_parse_options)
+        .map_err(|err| format!("could not parse {path} as Markdown: {err}"))?;
+    let includes = include_directives(&markdown_ast);
+    let mut code_blocks = get_all_code_blocks(markdown_ast).into_iter().peekable();
+    // Stdin has no directory, so its includes are relative to the working one.
+    let (canonical_path, dir) = if path == "-" {
+        (PathBuf::from(path), PathBuf::new())
+    } else {
+        let canonical_path =
+            std::fs::canonicalize(path).map_err(|err| format!("could not read {path}: {err}"))?;
+        let dir = Path::new(path)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+        (canonical_path, dir)
+    };
+    if let Some(idx) = including.iter().position(|file| *file == canonical_path) {
+        let cycle: Vec<String> = including[idx..]
+            .iter()
+            .chain([&canonical_path])
+            .map(|file| file.display().to_string())
+            .collect();
+        return Err(format!("cyclic bashtestmd:include: {}", cycle.join(" -> ")));
+    }
+    including.push(canonical_path);
 
-    let code_blocks = get_all_code_blocks(markdown_ast);
-    let commands = convert_code_blocks_into_commands(code_blocks, &args.tag);
-    let script = compile_commands_into_bash(commands);
+    for (offset, include) in includes {
+        let mut before = Vec::new();
+        while let Some(code_block) = code_blocks.next_if(|code_block| {
+            code_block
+                .position
+                .as_ref()
+                .is_some_and(|position| position.start.offset < offset)
+        }) {
+            before.push(code_block);
+        }
+        if !before.is_empty() {
+            inputs.push(Input {
+                path: path.to_string(),
+                contents: contents.clone(),
+                code_blocks: before,
+            });
+        }
+        let include_path = dir.join(include).display().to_string();
+        let include_contents = read_input(&include_path)?;
+        read_markdown(&include_path, include_contents, including, inputs)?;
+    }
+    inputs.push(Input {
+        path: path.to_string(),
+        contents,
+        code_blocks: code_blocks.collect(),
+    });
 
-    std::fs::write(&args.output, script).unwrap();
+    including.pop();
+    Ok(())
 }
 
-struct Command {
-    cmd: String,
-    long_running: bool,
-    expected_output: Option<String>,
-    wait_until: Option<String>,
-    exit_code: Option<i32>,
+/// The `<!-- bashtestmd:include PATH -->` directives of a Markdown document,
+/// as the offsets of the comments and the paths.
+fn include_directives(node: &mdast::Node) -> Vec<(usize, String)> {
+    if let mdast::Node::Html(html) = node {
+        let path = html
+            .value
+            .trim()
+            .strip_prefix("<!--")
+            .and_then(|comment| comment.strip_suffix("-->"))
+            .and_then(|comment| comment.trim().strip_prefix("bashtestmd:include "))
+            .map(str::trim);
+        return match (path, &html.position) {
+            (Some(path), Some(position)) if !path.is_empty() => {
+                vec![(position.start.offset, path.to_string())]
+            }
+            _ => Vec::new(),
+        };
+    }
+    node.children()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .flat_map(include_directives)
+        .collect()
 }
 
-impl Command {
-    fn new(cmd: &str) -> Self {
-        Self {
-            cmd: cmd.to_string(),
-            long_running: false,
-            expected_output: None,
-            wait_until: None,
-            exit_code: Some(0),
-        }
-    }
-
-    fn compile(&self, mut w: impl io::Write) -> io::Result<()> {
-        writeln!(
-            w,
-            "echo {}",
-            shell_escape::escape(format!("Running: '{}'", self.cmd).into())
-        )?;
-
-        if self.long_running {
-            if let Some(wait_until) = &self.wait_until {
-                writeln!(
-                    w,
-                    indoc!(
-                        r#"
-                        output=$(mktemp)
-                        export BASHTESTMD_LONG_RUNNING_OUTPUT=$output
-                        {} &> $output &
-                        background_process_pid=$!
-                        echo "Waiting for process with PID: $background_process_pid to have a match in $output"
-                        until grep -q -i {} $output
-                        do       
-                          if ! ps $background_process_pid > /dev/null 
-                          then
-                            echo "The background process died, output:" >&2
-                            cat $output
-                            exit 1
-                          fi
-                          echo -n "."
-                          sleep 5
-                        done
-                        echo ""
-                        "#
-                    ),
-                    self.cmd,
-                    shell_escape::escape(wait_until.into())
-                )?;
-            } else {
-                // No expected output, just run the command and wait two
-                // minutes. Very, very hackish.
-                writeln!(w, "{} &", self.cmd)?;
-                writeln!(w, "sleep 120")?;
+/// Reads the file at `path`, or stdin if it's `-`.
+fn read_input(path: &str) -> Result<String, String> {
+    let contents = if path == "-" {
+        std::io::read_to_string(std::io::stdin())
+    } else {
+        std::fs::read_to_string(path)
+    };
+    contents.map_err(|err| format!("could not read {path}: {err}"))
+}
+
+/// Writes `contents` to the file at `path`, or stdout if it's `-`.
+fn write_output(path: &str, contents: &str) -> Result<(), String> {
+    let written = if path == "-" {
+        std::io::stdout().write_all(contents.as_bytes())
+    } else {
+        std::fs::write(path, contents)
+    };
+    written.map_err(|err| format!("could not write {path}: {err}"))
+}
+
+/// The options of the `<!-- bashtestmd:config ... -->` directive at the top
+/// of a Markdown file, as names of command line options and their values,
+/// e.g. `<!-- bashtestmd:config tag=test-ci quiet-on-success -->` gives
+/// `[("tag", Some("test-ci")), ("quiet-on-success", None)]`. Values with
+/// spaces must be in double quotes.
+fn document_config(file_contents: &str) -> Vec<(String, Option<String>)> {
+    let Some(directive) = file_contents
+        .trim_start()
+        .strip_prefix("<!--")
+        .and_then(|comment| comment.split_once("-->"))
+        .and_then(|(comment, _)| comment.trim_start().strip_prefix("bashtestmd:config"))
+    else {
+        return Vec::new();
+    };
+
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut in_quotes = false;
+    for c in directive.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            c => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+
+    tokens
+        .into_iter()
+        .map(|token| match token.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (token, None),
+        })
+        .collect()
+}
+
+/// Writes the script to `output`, after passing it through the
+/// `--post-process` command if there's one. If that fails, the script is
+/// written as it is. Returns whether the post-processing and writing
+/// succeeded.
+fn write_script(args: &Args, output: &str, script: String) -> bool {
+    let (script, post_processed) = match &args.post_process {
+        None => (script, true),
+        Some(post_process) => match run_post_process(post_process, &script) {
+            Ok(processed) => (processed, true),
+            Err(err) => {
+                eprintln!(
+                    "Error: the --post-process command failed, writing the script without it: {err}"
+                );
+                (script, false)
             }
-            return Ok(());
-        }
-
-        if let Some(output) = &self.expected_output {
-            writeln!(
-                w,
-                indoc!(
-                    r#"
-                    output=$({})
-                    expected={}
-                    # Either of the two must be a substring of the other. This kinda protects us
-                    # against whitespace differences, trimming, etc.
-                    if ! [[ $output == *"$expected"* || $expected == *"$output"* ]]; then
-                        echo "'$expected' not found in text:"
-                        echo "'$output'"
-                        check_and_output_long_running_output
-                        echo "=========== END OF THE LONG RUNNING OUTPUT. Terminating..."
-                        exit 1
-                    fi
-                    "#
-                ),
-                self.cmd,
-                shell_escape::escape(output.into())
-            )?;
+        },
+    };
+    match write_output(output, &script) {
+        Ok(()) => post_processed,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            false
+        }
+    }
+}
+
+/// Writes the script to `--output`, or to a temporary file without it, and
+/// runs it with `--shell`, with its output going to ours. The temporary file
+/// is kept with `--preserve-temp`. Returns the exit code of the script.
+fn run_script(args: &Args, script: String) -> i32 {
+    let path = match &args.output {
+        Some(output) => PathBuf::from(output),
+        None => std::env::temp_dir().join(format!("bashtestmd-run-{}.sh", std::process::id())),
+    };
+    if !write_script(args, &path.to_string_lossy(), script) {
+        return 1;
+    }
+    // Like the shebang line, e.g. `/usr/bin/env bash`.
+    let mut shell = args.shell.split_whitespace();
+    let status = std::process::Command::new(shell.next().unwrap_or("bash"))
+        .args(shell)
+        .arg(&path)
+        .status();
+    if args.output.is_none() {
+        if args.preserve_temp {
+            eprintln!("Preserved the script: {}", path.display());
         } else {
-            writeln!(w, "{}", self.cmd)?;
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+    match status {
+        // Without an exit code if a signal terminated it.
+        Ok(status) => status.code().unwrap_or(1),
+        Err(err) => {
+            eprintln!("Error: could not run the script with {}: {err}", args.shell);
+            1
         }
+    }
+}
 
-        if let Some(exit_code) = self.exit_code {
-            writeln!(
-                w,
-                indoc!(
-                    r#"
-                    if [ $? -ne {0} ]; then
-                        echo "Expected exit code {0}, got $?"
-                        check_and_output_long_running_output
-                        exit 1
-                    fi
-                    "#,
-                ),
-                exit_code
-            )?;
+/// Runs `cmd` in a shell with the script on stdin, returning its stdout.
+fn run_post_process(cmd: &str, script: &str) -> Result<String, String> {
+    let mut child = std::process::Command::new("sh")
+        .args(["-c", cmd])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|err| err.to_string())?;
+    // From another thread, so that a command writing a lot of output before
+    // reading all of its input doesn't deadlock.
+    let mut stdin = child.stdin.take().unwrap();
+    let script = script.to_string();
+    let writer = std::thread::spawn(move || stdin.write_all(script.as_bytes()));
+    let processed = child.wait_with_output().map_err(|err| err.to_string())?;
+    // The command may exit without reading all of its input, so a write error
+    // only matters if it failed.
+    let write_result = writer.join().unwrap();
+    if !processed.status.success() {
+        return Err(format!("`{cmd}` exited with {}", processed.status));
+    }
+    write_result.map_err(|err| err.to_string())?;
+    String::from_utf8(processed.stdout).map_err(|err| err.to_string())
+}
+
+/// Keeps the code blocks that overlap with `changed_lines`, and the ones the
+/// kept blocks depend on.
+fn only_changed(code_blocks: Vec<mdast::Code>, changed_lines: &[usize]) -> Vec<mdast::Code> {
+    let has_token = |code_block: &mdast::Code, prefix: &str| {
+        fence_tokens(code_block)
+            .iter()
+            .any(|token| token.starts_with(prefix))
+    };
+    let mut keep: Vec<bool> = code_blocks
+        .iter()
+        .map(|code_block| {
+            let Some(position) = &code_block.position else {
+                return true;
+            };
+            // Definitions of outputs are needed by the blocks using them.
+            has_token(code_block, "bashtestmd:define-output=")
+                || changed_lines
+                    .iter()
+                    .any(|line| (position.start.line..=position.end.line).contains(line))
+        })
+        .collect();
+    // A block with `bashtestmd:output-follows` and the block with its output
+    // go together.
+    for idx in 0..code_blocks.len().saturating_sub(1) {
+        if has_token(&code_blocks[idx], "bashtestmd:output-follows") {
+            let either = keep[idx] || keep[idx + 1];
+            keep[idx] = either;
+            keep[idx + 1] = either;
         }
+    }
 
-        Ok(())
+    code_blocks
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(code_block, keep)| keep.then_some(code_block))
+        .collect()
+}
+
+/// The lines of `path` that changed since the git revision `rev`, including
+/// uncommitted changes.
+fn changed_lines(path: &str, rev: &str) -> Result<Vec<usize>, String> {
+    // From the directory of the file, which may be in another repository.
+    let path = Path::new(path);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let diff = std::process::Command::new("git")
+        .args(["diff", "--no-color", "--unified=0", rev, "--"])
+        .arg(path.file_name().unwrap_or_default())
+        .current_dir(dir)
+        .output()
+        .map_err(|err| err.to_string())?;
+    if !diff.status.success() {
+        return Err(String::from_utf8_lossy(&diff.stderr).trim().to_string());
     }
+    Ok(changed_lines_of_diff(&String::from_utf8_lossy(
+        &diff.stdout,
+    )))
 }
 
-fn compile_commands_into_bash(cmds: Vec<Command>) -> String {
-    let mut script = Vec::<u8>::new();
-    // Shebang.
-    writeln!(&mut script, "#!/usr/bin/env bash").unwrap();
-    writeln!(&mut script, r#"trap 'jobs -p | xargs -r kill' EXIT"#).unwrap();
-    writeln!(
-        &mut script,
-        indoc!(
-        r#"
-        check_and_output_long_running_output() {{
-            if [[ -n "$BASHTESTMD_LONG_RUNNING_OUTPUT" && -f "$BASHTESTMD_LONG_RUNNING_OUTPUT" ]]; then
-                echo "Output of the long running task:"
-                cat "$BASHTESTMD_LONG_RUNNING_OUTPUT"
-            fi
-        }}
-        "#
-        )
-    ).unwrap();
-
-    for cmd in cmds {
-        cmd.compile(&mut script).unwrap();
-    }
-    writeln!(&mut script, r#"echo "All tests passed!"; exit 0"#).unwrap();
-    String::from_utf8(script).unwrap()
+/// The lines of the new version of the file in `diff`, a `git diff` with no
+/// context lines, that are changed or next to removed lines.
+fn changed_lines_of_diff(diff: &str) -> Vec<usize> {
+    let mut lines = Vec::new();
+    // Hunk headers look like `@@ -<old start>,<old count> +<start>,<count> @@`,
+    // where the counts are omitted if they're 1.
+    for line in diff.lines() {
+        let Some(new_range) = line
+            .strip_prefix("@@ ")
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|range| range.strip_prefix('+'))
+        else {
+            continue;
+        };
+        let (start, count) = new_range.split_once(',').unwrap_or((new_range, "1"));
+        let (Ok(start), Ok(count)) = (start.parse::<usize>(), count.parse::<usize>()) else {
+            continue;
+        };
+        if count == 0 {
+            // Lines were only removed, after line `start`. Count that as a
+            // change of the lines around the removal.
+            lines.extend([start, start + 1]);
+        } else {
+            lines.extend(start..start + count);
+        }
+    }
+    lines
 }
 
-struct CodeBlockTags {
-    long_running: bool,
-    compare_output: bool,
-    exit_code: Option<i32>,
-    wait_until: Option<String>,
+/// Lists the tags of all code blocks, regardless of `--tag`, with the number
+/// of commands in the blocks with each tag, e.g.
+/// `{"tags":[{"tag":"test-ci","commands":3}]}`.
+fn plan_json(code_blocks: &[mdast::Code], prompt: &str) -> String {
+    // In the order the tags first appear in.
+    let mut tags: Vec<(String, usize)> = Vec::new();
+    for code_block in code_blocks {
+        let commands = code_block
+            .value
+            .lines()
+            .filter(|line| line.starts_with(prompt))
+            .count();
+        // The first token is the language of the block.
+        for token in fence_tokens(code_block).into_iter().skip(1) {
+            if token.starts_with("bashtestmd:") {
+                continue;
+            }
+            match tags.iter_mut().find(|(tag, _)| *tag == token) {
+                Some((_, count)) => *count += commands,
+                None => tags.push((token, commands)),
+            }
+        }
+    }
+
+    let tags: Vec<String> = tags
+        .into_iter()
+        .map(|(tag, commands)| format!(r#"{{"tag":{},"commands":{commands}}}"#, json_string(&tag)))
+        .collect();
+    format!(r#"{{"tags":[{}]}}"#, tags.join(","))
 }
 
-impl CodeBlockTags {
-    fn parse(code_block: &mdast::Code, only_tag: &str) -> Self {
-        let langs: Vec<String> = code_block
-            .lang
-            .as_deref()
-            .unwrap_or_default()
-            .split(',')
-            .map(str::to_string)
-            .collect();
+/// Checks the generated script with `bash -n`, and the commands in it with
+/// `shellcheck` if it's installed. Issues are reported with the line of the
+/// command in its input file, or `input` for generated code. Returns the
+/// number of issues.
+fn lint(input: &str, script: &str, source_map: &[SourceMapEntry]) -> usize {
+    let location = |entry: Option<&SourceMapEntry>| {
+        let file = entry
+            .and_then(|entry| entry.markdown_file.as_deref())
+            .unwrap_or(input);
+        match entry.and_then(|entry| entry.markdown_line) {
+            Some(line) => format!("{file}:{line}"),
+            None => format!("{file}: (generated code)"),
+        }
+    };
+    let mut issues = 0;
 
-        let mut tags = Self {
-            long_running: false,
-            compare_output: false,
-            exit_code: Some(0),
-            wait_until: None,
+    let syntax_errors = match check_syntax(script) {
+        Ok(syntax_errors) => syntax_errors,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            std::process::exit(1);
+        }
+    };
+    // Errors look like `<path>: line <N>: <message>`.
+    for error in syntax_errors.lines() {
+        let Some((_, rest)) = error.split_once(": line ") else {
+            continue;
         };
+        let (line, message) = rest.split_once(": ").unwrap_or((rest, ""));
+        let entry = line.parse::<usize>().ok().and_then(|line| {
+            source_map
+                .iter()
+                .take_while(|entry| entry.script_line <= line)
+                .last()
+        });
+        println!("{}: bash: {message}", location(entry));
+        issues += 1;
+    }
 
-        for (idx, lang) in langs.into_iter().enumerate() {
-            if lang == "bashtestmd:long-running" {
-                tags.long_running = true;
-            } else if lang == "bashtestmd:compare-output" {
-                tags.compare_output = true;
-            } else if lang == "bashtestmd:exit-code-ignore" {
-                tags.exit_code = None;
-            } else if lang.starts_with("bashtestmd:exit-code=") {
-                let exit_code = lang.split_once('=').unwrap().1.parse().unwrap();
-                tags.exit_code = Some(exit_code);
-            } else if lang.starts_with("bashtestmd:wait-until=") {
-                let wait_until = lang.split_once('=').unwrap().1.to_string();
-                tags.wait_until = Some(wait_until);
-            } else {
-                // Don't warn on the first `lang` tag of if the tag is the one marking blocks for bashtestmd to compile
-                // This ensures that (i.e. ```rust,test-ci```) should not generate warnings.
-                if idx != 0 && lang != only_tag {
-                    println!("Unknown bashtestmd tag, ignoring: {}", lang);
+    match std::process::Command::new("shellcheck")
+        .arg("--version")
+        .output()
+    {
+        Ok(_) => {
+            for entry in source_map {
+                // Each command on its own, so that the code generated around
+                // it doesn't add noise.
+                let shellcheck_issues = match shellcheck(&entry.cmd) {
+                    Ok(shellcheck_issues) => shellcheck_issues,
+                    Err(err) => {
+                        eprintln!("Error: {err}");
+                        std::process::exit(1);
+                    }
+                };
+                // Issues look like `-:<line>:<column>: <level>: <message>`.
+                for issue in shellcheck_issues.lines() {
+                    let message = issue.splitn(4, ':').nth(3).unwrap_or(issue).trim();
+                    println!("{}: shellcheck: {message}", location(Some(entry)));
+                    issues += 1;
                 }
             }
         }
+        Err(_) => println!("shellcheck is not installed, only checking the syntax with bash -n"),
+    }
 
-        tags
+    if issues == 0 {
+        println!("No issues found");
+    } else {
+        println!("Found {issues} issue(s)");
     }
+    issues
 }
 
-fn convert_code_blocks_into_commands(
-    code_blocks: Vec<mdast::Code>,
-    only_tag: &str,
-) -> Vec<Command> {
-    const PROMPT: &str = "$ ";
+/// The syntax errors of `script`, as reported by `bash -n`.
+fn check_syntax(script: &str) -> Result<String, String> {
+    let script_path =
+        std::env::temp_dir().join(format!("bashtestmd-lint-{}.sh", std::process::id()));
+    std::fs::write(&script_path, script)
+        .map_err(|err| format!("could not write {}: {err}", script_path.display()))?;
+    let bash = std::process::Command::new("bash")
+        .arg("-n")
+        .arg(&script_path)
+        .output();
+    let _ = std::fs::remove_file(&script_path);
+    let bash = bash.map_err(|err| format!("could not run bash -n: {err}"))?;
+    Ok(String::from_utf8_lossy(&bash.stderr).into_owned())
+}
 
-    let mut commands = Vec::new();
+/// The issues that shellcheck finds in the command `cmd`. Variables may be
+/// assigned and used in different commands, hence the excluded checks.
+fn shellcheck(cmd: &str) -> Result<String, String> {
+    let error = |err: std::io::Error| format!("could not run shellcheck: {err}");
+    let mut shellcheck = std::process::Command::new("shellcheck")
+        .args([
+            "--shell=bash",
+            "--format=gcc",
+            "--exclude=SC2034,SC2154",
+            "-",
+        ])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .map_err(error)?;
+    // Dropped right away, so that shellcheck sees the end of its input.
+    shellcheck
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(cmd.as_bytes())
+        .map_err(error)?;
+    let shellcheck = shellcheck.wait_with_output().map_err(error)?;
+    Ok(String::from_utf8_lossy(&shellcheck.stdout).into_owned())
+}
 
-    for code_block in code_blocks {
-        if !code_block
-            .lang
-            .as_deref()
-            .unwrap_or_default()
-            .contains(only_tag)
-        {
-            continue;
+/// Generates the script that runs the code blocks tagged with `tag`, of all
+/// input files in order. The commands are described in `report` if given.
+fn generate_script(
+    args: &Args,
+    inputs: &[Input],
+    tag: &str,
+    report: Option<&str>,
+) -> (String, Vec<SourceMapEntry>) {
+    let commands = prepare_commands(args, inputs, tag);
+    if let Some(report) = report {
+        if let Err(err) = write_output(report, &commands_json(&commands)) {
+            eprintln!("Error: {err}");
+            std::process::exit(1);
         }
-        let mut block_contains_command = false;
-        let tags = CodeBlockTags::parse(&code_block, only_tag);
+    }
+    compile_commands_into_bash(commands, &script_options(args))
+}
 
-        let mut cmd: Option<String> = None;
-        let mut output = String::new();
+/// Warns about the `--tag`s that select no code block of the inputs, which
+/// would give a script that passes without running anything, e.g. because
+/// the tag is misspelled. Returns how many there are.
+fn unmatched_tags(args: &Args, inputs: &[Input]) -> usize {
+    let mut unmatched = 0;
+    for tag in &args.tag {
+        let selected = inputs
+            .iter()
+            .flat_map(|input| &input.code_blocks)
+            .any(|code_block| is_selected(code_block, tag));
+        if selected {
+            continue;
+        }
+        unmatched += 1;
+        if args.quiet && !args.strict {
+            continue;
+        }
+        if tag.is_empty() {
+            eprintln!("Warning: no code block is in bash, sh or shell, check the info strings of the fences (e.g. ```bash)");
+        } else {
+            eprintln!("Warning: no code block is tagged with `{tag}`, check the info strings of the fences (e.g. ```bash,{tag})");
+        }
+    }
+    unmatched
+}
 
-        for line in code_block.value.lines() {
-            if let Some(cmd_string) = line.strip_prefix(PROMPT) {
-                if let Some(cmd) = cmd {
-                    commands.push(Command::new(&cmd));
+/// Converts the code blocks of every `--tag` without generating scripts,
+/// printing the issues with them and how many there are, including the
+/// `unmatched_tags` warned about. Returns that number.
+fn check(args: &Args, inputs: &[Input], unmatched_tags: usize) -> usize {
+    let mut errors = 0;
+    let mut warnings = unmatched_tags;
+    for tag in &args.tag {
+        for input in inputs {
+            match convert_code_blocks_into_commands(
+                input.code_blocks.clone(),
+                &conversion_options(args, tag, &input.path),
+            ) {
+                Ok(conversion) => warnings += conversion.warnings,
+                Err(err) => {
+                    errors += err.errors;
+                    warnings += err.warnings;
                 }
-                cmd = Some(cmd_string.to_string());
-                block_contains_command = true;
-            } else {
-                output.push_str(line);
-                output.push('\n');
             }
         }
-        if !block_contains_command {
-            println!(
-                "Warning: could not find command in block`:\n```\n{}\n```",
-                &code_block.value
-            );
-            println!("^^^^^ remove the tag {only_tag} from the block or add a command beginning with `{PROMPT}` to fix this warning");
-        }
-        if let Some(cmd) = cmd {
-            let mut cmd = Command::new(&cmd);
-            cmd.long_running = tags.long_running;
-            cmd.wait_until = tags.wait_until;
-            cmd.expected_output = if tags.compare_output {
-                Some(output)
-            } else {
-                None
+    }
+    let issues = errors + warnings;
+    if issues == 0 {
+        println!("No issues found");
+    } else {
+        println!("Found {issues} issue(s): {errors} error(s) and {warnings} warning(s)");
+    }
+    issues
+}
+
+fn conversion_options(args: &Args, tag: &str, file: &str) -> ConversionOptions {
+    ConversionOptions {
+        tag: tag.to_string(),
+        exclude_tags: args.exclude_tag.clone(),
+        defines: args.define.clone(),
+        werror: args.werror,
+        embed_output: args.embed_output,
+        prompt: args.prompt.clone(),
+        quiet: args.quiet,
+        verbose: args.verbose,
+        strict: args.strict,
+        file: file.to_string(),
+    }
+}
+
+/// The commands of the code blocks tagged with `tag`, of all input files in
+/// order, with the extra commands and settings from `args` applied.
+fn prepare_commands(args: &Args, inputs: &[Input], tag: &str) -> Vec<Command> {
+    let mut commands = Vec::new();
+    // Each file on its own, so that the commands know which file they come
+    // from.
+    for input in inputs {
+        let conversion_options = conversion_options(args, tag, &input.path);
+        let mut input_commands =
+            match convert_code_blocks_into_commands(input.code_blocks.clone(), &conversion_options)
+            {
+                Ok(conversion) => conversion.commands,
+                Err(err) => {
+                    eprintln!("{err}");
+                    std::process::exit(1);
+                }
             };
-            commands.push(cmd);
+        for cmd in &mut input_commands {
+            cmd.set_file(&input.path);
+        }
+        // Stdin has no directory, so paths stay relative to the working one.
+        if args.paths_relative_to == PathsRelativeTo::Input && input.path != "-" {
+            let input_path = std::fs::canonicalize(&input.path).unwrap();
+            let input_dir = input_path.parent().unwrap();
+            for cmd in &mut input_commands {
+                cmd.resolve_paths(input_dir);
+            }
         }
+        commands.extend(input_commands);
+    }
+    if let Some(commands_file) = &args.commands_file {
+        let file_contents = match read_input(commands_file) {
+            Ok(file_contents) => file_contents,
+            Err(err) => {
+                eprintln!("Error: {err}");
+                std::process::exit(1);
+            }
+        };
+        commands.extend(read_commands_file(&file_contents));
+    }
+    if let Some(baseline_dir) = &args.baseline_dir {
+        assign_baselines(&mut commands, Path::new(baseline_dir));
     }
-
     commands
 }
 
-/// Ordered list of all code blocks in the Markdown file.
-fn get_all_code_blocks(markdown_ast: mdast::Node) -> Vec<mdast::Code> {
-    let mut code_blocks = Vec::new();
+fn script_options(args: &Args) -> ScriptOptions {
+    ScriptOptions {
+        preserve_temp: args.preserve_temp,
+        mismatch_context: args.mismatch_context,
+        atomic: args.atomic,
+        before: args.before.iter().map(|cmd| Command::new(cmd)).collect(),
+        after: args.after.iter().map(|cmd| Command::new(cmd)).collect(),
+        docker: args.docker.clone(),
+        working_dir: args.working_dir.clone(),
+        cache_dir: args.cache_dir.clone(),
+        quiet_on_success: args.quiet_on_success,
+        wait_progress: args.wait_progress,
+        max_total_duration: args.max_total_duration,
+        max_runtime: args.max_runtime,
+        step: args.step,
+        isolate: args.isolate,
+        messages_to: args.messages_to,
+        log_file: args.log_file.clone(),
+        default_background_delay: args.default_background_delay,
+        keep_going: args.keep_going,
+        pipefail: args.pipefail,
+        shell: args.shell.clone(),
+        tap: args.tap,
+        dump_long_running_output: args.dump_long_running_output,
+        success_message: (!args.no_success_message).then(|| args.success_message.clone()),
+        timings: args.timings,
+    }
+}
 
-    let mut nodes: VecDeque<mdast::Node> = markdown_ast
-        .children()
-        .cloned()
-        .unwrap_or_default()
-        .into_iter()
+/// Runs the commands of the code blocks tagged with `tag`, and replaces the
+/// content of the blocks following the ones tagged with
+/// `bashtestmd:output-follows` with the output of their commands. Returns
+/// the updated Markdown, or `None` if the script failed.
+fn embed_output(args: &Args, input: &Input, tag: &str) -> Result<Option<String>, String> {
+    let file_contents = &input.contents;
+    let commands = prepare_commands(args, std::slice::from_ref(input), tag);
+    let mut positions: Vec<Position> = commands
+        .iter()
+        .filter_map(|cmd| cmd.embed_output().cloned())
         .collect();
+    let (script, _) = compile_commands_into_bash(commands, &script_options(args));
 
-    while let Some(next_node) = nodes.pop_front() {
-        if let mdast::Node::Code(code_node) = next_node {
-            code_blocks.push(code_node);
-        } else {
-            let children = next_node.children().map(Vec::as_slice).unwrap_or_default();
-            for child in children.iter() {
-                nodes.push_front(child.clone());
+    let embed_dir = std::env::temp_dir().join(format!("bashtestmd-embed-{}", std::process::id()));
+    let outputs = run_embed_script(&embed_dir, script, &positions);
+    // Also if running the script failed.
+    let _ = std::fs::remove_dir_all(&embed_dir);
+    let Some(outputs) = outputs? else {
+        return Ok(None);
+    };
+
+    // From the end, so that the offsets of the blocks before stay valid.
+    let mut markdown = file_contents.to_string();
+    positions.reverse();
+    for (position, output) in positions.iter().zip(outputs.into_iter().rev()) {
+        let Some(output) = output else {
+            continue;
+        };
+        let block = &file_contents[position.start.offset..position.end.offset];
+        // The fences may be indented, e.g. in a list.
+        let indent = " ".repeat(position.start.column - 1);
+        let (Some(opening_end), Some(closing_start)) = (block.find('\n'), block.rfind('\n')) else {
+            continue;
+        };
+        let mut content = String::new();
+        for line in output.lines() {
+            if !line.is_empty() {
+                content.push_str(&indent);
             }
+            content.push_str(line);
+            content.push('\n');
         }
+        markdown.replace_range(
+            position.start.offset + opening_end + 1..position.start.offset + closing_start + 1,
+            &content,
+        );
     }
+    Ok(Some(markdown))
+}
 
-    code_blocks
+/// Runs the script of `--embed-output` in `embed_dir`, returning the outputs
+/// that it wrote there for the blocks at `positions`, or `None` if it failed.
+fn run_embed_script(
+    embed_dir: &Path,
+    script: String,
+    positions: &[Position],
+) -> Result<Option<Vec<Option<String>>>, String> {
+    std::fs::create_dir_all(embed_dir)
+        .map_err(|err| format!("could not create {}: {err}", embed_dir.display()))?;
+    let script_path = embed_dir.join("script.sh");
+    std::fs::write(&script_path, script)
+        .map_err(|err| format!("could not write {}: {err}", script_path.display()))?;
+    let status = std::process::Command::new("bash")
+        .arg(&script_path)
+        .env("BASHTESTMD_EMBED_DIR", embed_dir)
+        .status()
+        .map_err(|err| format!("could not run the script with bash: {err}"))?;
+    if !status.success() {
+        return Ok(None);
+    }
+    Ok(Some(
+        positions
+            .iter()
+            .map(|position| {
+                std::fs::read_to_string(embed_dir.join(position.start.offset.to_string())).ok()
+            })
+            .collect(),
+    ))
+}
+
+/// The path of the script for `tag` in `--split-by-tag` mode: the tag is
+/// appended to the file stem of `output`, e.g. `demo.sh` becomes
+/// `demo-test-ci.sh`.
+fn split_output_path(output: &str, tag: &str) -> String {
+    let tag: String = tag
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let path = Path::new(output);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{stem}-{tag}.{}", extension.to_string_lossy()),
+        None => format!("{stem}-{tag}"),
+    };
+    path.with_file_name(file_name).display().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn split_output_paths_have_the_tag_before_the_extension() {
+        assert_eq!(split_output_path("demo.sh", "test-ci"), "demo-test-ci.sh");
+        assert_eq!(split_output_path("out/demo", "test-ci"), "out/demo-test-ci");
+        assert_eq!(split_output_path("demo.sh", "a/b c"), "demo-a_b_c.sh");
+    }
+
+    /// The code blocks of `markdown`.
+    fn code_blocks(markdown: &str) -> Vec<mdast::Code> {
+        get_all_code_blocks(markdown::to_mdast(markdown, &markdown::ParseOptions::gfm()).unwrap())
+    }
+
+    #[test]
+    fn changed_lines_are_read_from_the_hunk_headers() {
+        let diff = indoc! {"
+            diff --git a/README.md b/README.md
+            --- a/README.md
+            +++ b/README.md
+            @@ -3 +3 @@ Title
+            -old
+            +new
+            @@ -10,0 +11,2 @@
+            +added
+            +added
+            @@ -20,2 +21,0 @@
+            -removed
+            -removed
+        "};
+        assert_eq!(changed_lines_of_diff(diff), [3, 11, 12, 21, 22]);
+    }
+
+    #[test]
+    fn only_changed_keeps_the_changed_blocks_and_their_outputs() {
+        let markdown = indoc! {"
+            ```sh,test-ci
+            $ echo unchanged
+            ```
+
+            ```sh,test-ci,bashtestmd:output-follows
+            $ echo changed
+            ```
+
+            ```text
+            changed
+            ```
+        "};
+        let kept = only_changed(code_blocks(markdown), &[6]);
+        let values: Vec<&str> = kept.iter().map(|block| block.value.as_str()).collect();
+        assert_eq!(values, ["$ echo changed", "changed"]);
+        assert!(only_changed(code_blocks(markdown), &[]).is_empty());
+    }
 }
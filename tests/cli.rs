@@ -0,0 +1,777 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+use indoc::indoc;
+
+/// Runs the `bashtestmd` binary with `args`, writing `stdin` to its standard
+/// input.
+fn bashtestmd(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bashtestmd"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// A new directory for the files of the test `name`.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bashtestmd-{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+const README: &str = indoc! {"
+    ```sh,test-ci
+    $ echo hello
+    ```
+"};
+
+#[test]
+fn reads_stdin_and_writes_stdout_without_output() {
+    let output = bashtestmd(&["--input", "-", "--tag", "test-ci"], README);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let script = stdout(&output);
+    assert!(script.starts_with("#!"), "{script}");
+    assert!(script.contains("echo hello"), "{script}");
+}
+
+#[test]
+fn writes_stdout_with_a_dash_as_output() {
+    let output = bashtestmd(
+        &["--input", "-", "--output", "-", "--tag", "test-ci"],
+        README,
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let script = stdout(&output);
+    assert!(script.contains("echo hello"), "{script}");
+}
+
+#[test]
+fn missing_inputs_are_reported_with_their_path() {
+    let output = bashtestmd(&["--input", "no-such-file.md", "--tag", "test-ci"], "");
+    assert_eq!(output.status.code(), Some(1));
+    let messages = stderr(&output);
+    assert!(
+        messages.contains("could not read no-such-file.md"),
+        "{messages}"
+    );
+    assert!(!messages.contains("panicked"), "{messages}");
+}
+
+#[test]
+fn repeated_inputs_are_concatenated_in_order() {
+    let dir = temp_dir("inputs");
+    let install = dir.join("install.md");
+    let verify = dir.join("verify.md");
+    std::fs::write(
+        &install,
+        indoc! {"
+            ```sh,test-ci
+            $ echo install 1
+            ```
+
+            ```sh,test-ci
+            $ echo install 2
+            ```
+        "},
+    )
+    .unwrap();
+    std::fs::write(
+        &verify,
+        indoc! {"
+            ```sh,test-ci
+            $ echo verify
+            ```
+        "},
+    )
+    .unwrap();
+    let output = bashtestmd(
+        &[
+            "--input",
+            verify.to_str().unwrap(),
+            "--input",
+            install.to_str().unwrap(),
+            "--tag",
+            "test-ci",
+        ],
+        "",
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let script = stdout(&output);
+    let positions: Vec<usize> = ["echo verify", "echo install 1", "echo install 2"]
+        .iter()
+        .map(|cmd| script.find(cmd).unwrap())
+        .collect();
+    assert!(positions.is_sorted(), "{script}");
+}
+
+const UNKNOWN_TAG: &str = indoc! {"
+    ```sh,test-ci,bashtestmd:typo
+    $ echo hello
+    ```
+"};
+
+#[test]
+fn warnings_go_to_stderr() {
+    let output = bashtestmd(&["--input", "-", "--tag", "test-ci"], UNKNOWN_TAG);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let messages = stderr(&output);
+    assert!(
+        messages.contains("Unknown bashtestmd tag, ignoring: bashtestmd:typo"),
+        "{messages}"
+    );
+    let script = stdout(&output);
+    assert!(!script.contains("Unknown bashtestmd tag"), "{script}");
+}
+
+#[test]
+fn quiet_hides_warnings() {
+    let output = bashtestmd(
+        &["--input", "-", "--tag", "test-ci", "--quiet"],
+        UNKNOWN_TAG,
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stderr(&output), "");
+    let script = stdout(&output);
+    assert!(!script.contains("Unknown bashtestmd tag"), "{script}");
+}
+
+#[test]
+fn verbose_prints_the_converted_code_blocks() {
+    let output = bashtestmd(&["--input", "-", "--tag", "test-ci", "--verbose"], README);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let messages = stderr(&output);
+    assert!(
+        messages.contains("Converting the block: sh,test-ci"),
+        "{messages}"
+    );
+}
+
+#[test]
+fn strict_fails_on_unknown_tags() {
+    let output = bashtestmd(
+        &["--input", "-", "--tag", "test-ci", "--strict"],
+        UNKNOWN_TAG,
+    );
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn strict_ignores_tags_without_the_prefix() {
+    let markdown = indoc! {"
+        ```sh,rust,test-ci
+        $ echo hello
+        ```
+    "};
+    let output = bashtestmd(&["--input", "-", "--tag", "test-ci", "--strict"], markdown);
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn report_writes_the_json_description_of_the_commands() {
+    let dir = temp_dir("report");
+    let report = dir.join("report.json");
+    let output = bashtestmd(
+        &[
+            "--input",
+            "-",
+            "--tag",
+            "test-ci",
+            "--report",
+            report.to_str().unwrap(),
+        ],
+        README,
+    );
+    let json = std::fs::read_to_string(&report).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("echo hello"));
+    assert!(
+        json.starts_with(r#"[{"cmd":"echo hello","line":2,"#),
+        "{json}"
+    );
+}
+
+#[test]
+fn shell_is_the_shebang_verbatim() {
+    let output = bashtestmd(
+        &["--input", "-", "--tag", "test-ci", "--shell", "/bin/bash"],
+        README,
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let script = stdout(&output);
+    assert!(script.starts_with("#!/bin/bash\n"), "{script}");
+}
+
+#[test]
+fn warnings_have_the_file_and_the_line() {
+    let dir = temp_dir("location");
+    let readme = dir.join("README.md");
+    std::fs::write(&readme, format!("# Title\n\n{UNKNOWN_TAG}")).unwrap();
+    let path = readme.to_str().unwrap();
+    let output = bashtestmd(&["--input", path, "--tag", "test-ci"], "");
+    std::fs::remove_dir_all(&dir).unwrap();
+    let messages = stderr(&output);
+    assert!(
+        messages.contains(&format!(
+            "{path}:3: Unknown bashtestmd tag, ignoring: bashtestmd:typo"
+        )),
+        "{messages}"
+    );
+}
+
+#[test]
+fn includes_splice_the_code_blocks_of_other_files() {
+    let dir = temp_dir("include");
+    std::fs::create_dir_all(dir.join("snippets")).unwrap();
+    std::fs::write(
+        dir.join("snippets/install.md"),
+        indoc! {"
+            ```sh,test-ci
+            $ echo included
+            ```
+        "},
+    )
+    .unwrap();
+    let readme = dir.join("README.md");
+    std::fs::write(
+        &readme,
+        indoc! {"
+            ```sh,test-ci
+            $ echo before
+            ```
+
+            <!-- bashtestmd:include snippets/install.md -->
+
+            ```sh,test-ci
+            $ echo after
+            ```
+        "},
+    )
+    .unwrap();
+    let output = bashtestmd(
+        &["--input", readme.to_str().unwrap(), "--tag", "test-ci"],
+        "",
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let script = stdout(&output);
+    let positions: Vec<usize> = ["echo before", "echo included", "echo after"]
+        .iter()
+        .map(|cmd| script.find(cmd).unwrap())
+        .collect();
+    assert!(positions.is_sorted(), "{script}");
+}
+
+#[test]
+fn cyclic_includes_are_errors() {
+    let dir = temp_dir("include-cycle");
+    std::fs::write(dir.join("a.md"), "<!-- bashtestmd:include b.md -->\n").unwrap();
+    std::fs::write(dir.join("b.md"), "<!-- bashtestmd:include a.md -->\n").unwrap();
+    let a = dir.join("a.md");
+    let output = bashtestmd(&["--input", a.to_str().unwrap(), "--tag", "test-ci"], "");
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let messages = stderr(&output);
+    assert!(messages.contains("cyclic bashtestmd:include"), "{messages}");
+}
+
+const LANGUAGES: &str = indoc! {"
+    ```bash
+    $ echo bash
+    ```
+
+    ```rust,test-ci
+    $ echo rust
+    ```
+"};
+
+#[test]
+fn shell_blocks_run_without_tag() {
+    let output = bashtestmd(&["--input", "-"], LANGUAGES);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let script = stdout(&output);
+    assert!(script.contains("echo bash"), "{script}");
+    assert!(!script.contains("echo rust"), "{script}");
+}
+
+#[test]
+fn tag_overrides_the_shell_languages() {
+    let output = bashtestmd(&["--input", "-", "--tag", "test-ci"], LANGUAGES);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let script = stdout(&output);
+    assert!(!script.contains("echo bash"), "{script}");
+    assert!(script.contains("echo rust"), "{script}");
+}
+
+#[test]
+fn unreadable_inputs_are_errors_not_panics() {
+    let dir = temp_dir("unreadable");
+    let binary = dir.join("binary.md");
+    std::fs::write(&binary, [0xff, 0xfe, 0x00]).unwrap();
+    for path in [dir.to_str().unwrap(), binary.to_str().unwrap()] {
+        let output = bashtestmd(&["--input", path, "--tag", "test-ci"], "");
+        assert_eq!(output.status.code(), Some(1));
+        let messages = stderr(&output);
+        assert!(
+            messages.contains(&format!("could not read {path}")),
+            "{messages}"
+        );
+        assert!(!messages.contains("panicked"), "{messages}");
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn list_prints_the_commands_instead_of_the_script() {
+    let markdown = indoc! {"
+        ```sh,test-ci,bashtestmd:exit-code=1
+        $ false
+        ```
+
+        ```sh,test-ci
+        $ echo hello
+        ```
+    "};
+    let output = bashtestmd(&["--input", "-", "--tag", "test-ci", "--list"], markdown);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        indoc! {"
+            1. false (-:2)
+               exit code: 1
+            2. echo hello (-:6)
+               exit code: 0
+        "}
+    );
+}
+
+#[test]
+fn tags_matching_no_code_block_are_warned_about() {
+    let output = bashtestmd(&["--input", "-", "--tag", "tset-ci"], README);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let messages = stderr(&output);
+    assert!(
+        messages.contains("Warning: no code block is tagged with `tset-ci`"),
+        "{messages}"
+    );
+}
+
+#[test]
+fn tags_matching_no_code_block_fail_with_strict() {
+    let output = bashtestmd(&["--input", "-", "--tag", "tset-ci", "--strict"], README);
+    assert_eq!(output.status.code(), Some(1));
+    let messages = stderr(&output);
+    assert!(messages.contains("`tset-ci`"), "{messages}");
+}
+
+#[test]
+fn tags_matching_code_blocks_arent_warned_about() {
+    let output = bashtestmd(&["--input", "-", "--tag", "test-ci"], README);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn success_message_flags_change_the_last_line() {
+    let output = bashtestmd(
+        &[
+            "--input",
+            "-",
+            "--tag",
+            "test-ci",
+            "--success-message",
+            "Done",
+        ],
+        README,
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let script = stdout(&output);
+    assert!(script.contains("Done"), "{script}");
+    assert!(!script.contains("All tests passed!"), "{script}");
+
+    let output = bashtestmd(
+        &["--input", "-", "--tag", "test-ci", "--no-success-message"],
+        README,
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let script = stdout(&output);
+    assert!(!script.contains("All tests passed!"), "{script}");
+}
+
+#[test]
+fn exclude_tag_can_be_repeated() {
+    let markdown = indoc! {"
+        ```bash
+        $ echo runs
+        ```
+
+        ```bash,illustrative
+        $ echo illustrative
+        ```
+
+        ```bash,slow
+        $ echo slow
+        ```
+    "};
+    let output = bashtestmd(
+        &[
+            "--input",
+            "-",
+            "--exclude-tag",
+            "illustrative",
+            "--exclude-tag",
+            "slow",
+        ],
+        markdown,
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let script = stdout(&output);
+    assert!(script.contains("echo runs"), "{script}");
+    assert!(!script.contains("echo illustrative"), "{script}");
+    assert!(!script.contains("echo slow"), "{script}");
+}
+
+#[test]
+fn run_exits_with_zero_if_the_document_passes() {
+    let output = bashtestmd(&["--input", "-", "--tag", "test-ci", "--run"], README);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(stdout(&output).contains("hello"));
+}
+
+#[test]
+fn run_exits_with_the_code_of_a_failed_command() {
+    let markdown = indoc! {"
+        ```sh,test-ci
+        $ false
+        ```
+    "};
+    let output = bashtestmd(&["--input", "-", "--tag", "test-ci", "--run"], markdown);
+    // COMMAND_FAILED.
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+}
+
+#[test]
+fn run_keeps_the_script_with_output() {
+    let dir = temp_dir("run-output");
+    let path = dir.join("test.sh");
+    let output = bashtestmd(
+        &[
+            "--input",
+            "-",
+            "--tag",
+            "test-ci",
+            "--run",
+            "--output",
+            path.to_str().unwrap(),
+        ],
+        README,
+    );
+    let script = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert!(script.contains("echo hello"), "{script}");
+}
+
+#[test]
+fn defines_replace_their_placeholders() {
+    let markdown = indoc! {"
+        ```sh,test-ci
+        $ echo {{GREETING}}
+        ```
+    "};
+    let output = bashtestmd(
+        &[
+            "--input",
+            "-",
+            "--tag",
+            "test-ci",
+            "--define",
+            "GREETING=hello",
+        ],
+        markdown,
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("echo hello"));
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn defines_need_a_name_and_a_value() {
+    let output = bashtestmd(&["--input", "-", "--define", "GREETING"], README);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+    assert!(stderr(&output).contains("expected NAME=VALUE"));
+}
+
+#[test]
+fn split_by_tag_writes_a_script_per_tag() {
+    let dir = temp_dir("split");
+    let output = dir.join("demo.sh");
+    let markdown = indoc! {"
+        ```sh,unit
+        $ echo unit
+        ```
+
+        ```sh,e2e
+        $ echo e2e
+        ```
+    "};
+    let result = bashtestmd(
+        &[
+            "--input",
+            "-",
+            "--split-by-tag",
+            "--tag",
+            "unit",
+            "--tag",
+            "e2e",
+            "--output",
+            output.to_str().unwrap(),
+        ],
+        markdown,
+    );
+    let unit = std::fs::read_to_string(dir.join("demo-unit.sh")).unwrap();
+    let e2e = std::fs::read_to_string(dir.join("demo-e2e.sh")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(result.status.success(), "{}", stderr(&result));
+    assert!(
+        unit.contains("echo unit") && !unit.contains("echo e2e"),
+        "{unit}"
+    );
+    assert!(
+        e2e.contains("echo e2e") && !e2e.contains("echo unit"),
+        "{e2e}"
+    );
+}
+
+#[test]
+fn split_by_tag_rejects_tags_with_the_same_path() {
+    let output = bashtestmd(
+        &[
+            "--input",
+            "-",
+            "--split-by-tag",
+            "--tag",
+            "a/b",
+            "--tag",
+            "a b",
+            "--output",
+            "demo.sh",
+        ],
+        README,
+    );
+    assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("would both be written to 'demo-a_b.sh'"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn lint_reports_syntax_errors_with_their_line() {
+    let dir = temp_dir("lint");
+    let readme = dir.join("README.md");
+    std::fs::write(
+        &readme,
+        indoc! {"
+            ```sh,test-ci
+            $ echo fine
+            ```
+
+            ```sh,test-ci
+            $ if true; then
+            ```
+        "},
+    )
+    .unwrap();
+    let path = readme.to_str().unwrap();
+    let broken = bashtestmd(&["--input", path, "--tag", "test-ci", "--lint"], "");
+    let fine = bashtestmd(&["--input", "-", "--tag", "test-ci", "--lint"], README);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(broken.status.code(), Some(1), "{}", stderr(&broken));
+    let report = stdout(&broken);
+    assert!(
+        report.contains(&format!("{path}:6: bash: syntax error")),
+        "{report}"
+    );
+    assert!(report.contains("Found 1 issue(s)"), "{report}");
+    assert!(fine.status.success(), "{}", stdout(&fine));
+    assert!(stdout(&fine).contains("No issues found"));
+}
+
+#[test]
+fn lint_reports_an_unwritable_temporary_directory() {
+    let dir = temp_dir("lint-tmpdir");
+    let readme = dir.join("README.md");
+    std::fs::write(&readme, README).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_bashtestmd"))
+        .args([
+            "--input",
+            readme.to_str().unwrap(),
+            "--tag",
+            "test-ci",
+            "--lint",
+        ])
+        .env("TMPDIR", dir.join("missing"))
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let messages = stderr(&output);
+    assert!(messages.starts_with("Error: could not write"), "{messages}");
+    assert!(!messages.contains("panicked"), "{messages}");
+}
+
+#[test]
+fn only_changed_runs_the_blocks_with_uncommitted_changes() {
+    let dir = temp_dir("only-changed");
+    let git = |args: &[&str]| {
+        let status = Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(&dir)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {args:?}");
+    };
+    let readme = dir.join("README.md");
+    let markdown = indoc! {"
+        ```sh,test-ci
+        $ echo first
+        ```
+
+        ```sh,test-ci
+        $ echo second
+        ```
+    "};
+    std::fs::write(&readme, markdown).unwrap();
+    git(&["init", "-q"]);
+    git(&["add", "README.md"]);
+    git(&["commit", "-q", "-m", "README"]);
+    std::fs::write(&readme, markdown.replace("second", "changed")).unwrap();
+    let output = bashtestmd(
+        &[
+            "--input",
+            readme.to_str().unwrap(),
+            "--tag",
+            "test-ci",
+            "--only-changed",
+        ],
+        "",
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let script = stdout(&output);
+    assert!(script.contains("echo changed"), "{script}");
+    assert!(!script.contains("echo first"), "{script}");
+}
+
+const EMBEDDED: &str = indoc! {"
+    # Greeting
+
+    ```sh,test-ci,bashtestmd:output-follows
+    $ echo hello
+    ```
+
+    ```text
+    stale
+    ```
+"};
+
+#[test]
+fn embed_output_writes_the_outputs_into_the_document() {
+    let dir = temp_dir("embed");
+    let readme = dir.join("README.md");
+    std::fs::write(&readme, EMBEDDED).unwrap();
+    let output = bashtestmd(
+        &[
+            "--input",
+            readme.to_str().unwrap(),
+            "--tag",
+            "test-ci",
+            "--embed-output",
+        ],
+        "",
+    );
+    let markdown = std::fs::read_to_string(&readme).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(markdown, EMBEDDED.replace("stale", "hello"));
+}
+
+#[test]
+fn embed_output_keeps_the_document_if_the_script_fails() {
+    let dir = temp_dir("embed-failure");
+    let readme = dir.join("README.md");
+    let markdown = EMBEDDED.replace("echo hello", "false");
+    std::fs::write(&readme, &markdown).unwrap();
+    let output = bashtestmd(
+        &[
+            "--input",
+            readme.to_str().unwrap(),
+            "--tag",
+            "test-ci",
+            "--embed-output",
+        ],
+        "",
+    );
+    let updated = std::fs::read_to_string(&readme).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("was not updated"),
+        "{}",
+        stderr(&output)
+    );
+    assert_eq!(updated, markdown);
+}
+
+#[test]
+fn embed_output_reports_an_unwritable_temporary_directory() {
+    let dir = temp_dir("embed-tmpdir");
+    let readme = dir.join("README.md");
+    std::fs::write(&readme, EMBEDDED).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_bashtestmd"))
+        .args([
+            "--input",
+            readme.to_str().unwrap(),
+            "--tag",
+            "test-ci",
+            "--embed-output",
+        ])
+        .env("TMPDIR", readme.join("not-a-directory"))
+        .output()
+        .unwrap();
+    let markdown = std::fs::read_to_string(&readme).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let messages = stderr(&output);
+    assert!(
+        messages.starts_with("Error: could not create"),
+        "{messages}"
+    );
+    assert!(!messages.contains("panicked"), "{messages}");
+    assert_eq!(markdown, EMBEDDED);
+}